@@ -0,0 +1,308 @@
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read, read_dir, rename};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, error, info, warn};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::keystore;
+
+const INDEX_FILE: &str = "index.json";
+const INDEX_VERSION: u32 = 1;
+
+pub const USERNAME_MIN_LEN: usize = 1;
+pub const USERNAME_MAX_LEN: usize = 32;
+
+/// Serializes every read-modify-write of the index between commands.
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+/// An account as recorded in `accounts/index.json`. The keystore lives at
+/// `accounts/<id>.enc` and the account's other data under `accounts/<id>/`,
+/// so nothing the user types ever becomes part of a path.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccountEntry {
+    pub username: String,
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Index {
+    version: u32,
+    /// Keyed by `lookup_key(username)`.
+    accounts: BTreeMap<String, AccountEntry>,
+}
+
+impl AccountEntry {
+    pub fn keystore_path(&self, accounts_dir: &Path) -> PathBuf {
+        accounts_dir.join(format!("{}.enc", self.id))
+    }
+
+    pub fn data_dir(&self, accounts_dir: &Path) -> PathBuf {
+        accounts_dir.join(&self.id)
+    }
+}
+
+/// Applies the username policy and returns the canonical display form.
+///
+/// Usernames are NFKC-normalized and trimmed, must be 1 to 32 characters,
+/// and may contain letters, digits, single spaces, `_`, `-` and `.`. They may
+/// not start or end with `.` or `-`.
+pub fn normalize_username(raw: &str) -> Result<String, String> {
+    let username: String = raw.nfkc().collect::<String>().trim().to_string();
+    let reject = |reason: &str| {
+        error!("Username rejected: {}", reason);
+        format!("Invalid username: {}", reason)
+    };
+    let len = username.chars().count();
+    if len < USERNAME_MIN_LEN {
+        return Err(reject("it cannot be empty"));
+    }
+    if len > USERNAME_MAX_LEN {
+        return Err(reject(&format!("it must be at most {} characters", USERNAME_MAX_LEN)));
+    }
+    if let Some(c) = username.chars().find(|c| !(c.is_alphanumeric() || matches!(c, ' ' | '_' | '-' | '.'))) {
+        return Err(reject(&format!("character {:?} is not allowed", c)));
+    }
+    if username.contains("  ") {
+        return Err(reject("it cannot contain consecutive spaces"));
+    }
+    if username.starts_with(['.', '-']) || username.ends_with(['.', '-']) {
+        return Err(reject("it cannot start or end with '.' or '-'"));
+    }
+    Ok(username)
+}
+
+/// Case-insensitive key, so `Alice` and `alice` are the same account.
+fn lookup_key(username: &str) -> String {
+    username.to_lowercase()
+}
+
+/// Whether two usernames name the same account. Names that fail the policy
+/// never match anything.
+pub fn same_account(a: &str, b: &str) -> bool {
+    match (normalize_username(a), normalize_username(b)) {
+        (Ok(a), Ok(b)) => lookup_key(&a) == lookup_key(&b),
+        _ => false,
+    }
+}
+
+fn new_id() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn is_id(name: &str) -> bool {
+    name.len() == 32 && name.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase())
+}
+
+/// Reads the index without changing anything on disk.
+fn load(accounts_dir: &Path) -> Result<Index, String> {
+    let path = accounts_dir.join(INDEX_FILE);
+    let index = if path.exists() {
+        let data = read(&path).map_err(|e| {
+            let err = format!("Failed to read account index: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        serde_json::from_slice(&data).map_err(|e| {
+            let err = format!("Account index is corrupted: {}", e);
+            error!("{}", err);
+            err
+        })?
+    } else {
+        Index { version: INDEX_VERSION, ..Default::default() }
+    };
+    Ok(index)
+}
+
+fn store(accounts_dir: &Path, index: &Index) -> Result<(), String> {
+    let data = serde_json::to_vec_pretty(index).map_err(|e| {
+        let err = format!("Account index serialization failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    keystore::save(&accounts_dir.join(INDEX_FILE), &data)
+}
+
+/// Moves `accounts/<username>.enc` files from before the index existed to
+/// random ids. Names that fail the policy are left alone and logged.
+fn migrate_legacy(accounts_dir: &Path, index: &mut Index) -> Result<bool, String> {
+    let known: Vec<String> = index.accounts.values().map(|entry| format!("{}.enc", entry.id)).collect();
+    let entries = read_dir(accounts_dir).map_err(|e| {
+        let err = format!("Failed to read accounts dir: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    let mut migrated = false;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if !path.is_file() || !file_name.ends_with(".enc") || known.contains(&file_name) {
+            continue;
+        }
+        let stem = file_name.trim_end_matches(".enc");
+        if is_id(stem) {
+            // Written under an id whose index entry was since replaced.
+            warn!("Ignoring orphaned account file {:?}", path);
+            continue;
+        }
+        let username = match normalize_username(stem) {
+            Ok(username) if !index.accounts.contains_key(&lookup_key(&username)) => username,
+            _ => {
+                warn!("Leaving legacy account file {:?} unmigrated", path);
+                continue;
+            }
+        };
+        let account = AccountEntry { username: username.clone(), id: new_id() };
+        rename(&path, account.keystore_path(accounts_dir)).map_err(|e| {
+            let err = format!("Failed to migrate account {}: {}", username, e);
+            error!("{}", err);
+            err
+        })?;
+        let legacy_data_dir = accounts_dir.join(stem);
+        if legacy_data_dir.is_dir() {
+            rename(&legacy_data_dir, account.data_dir(accounts_dir)).map_err(|e| {
+                let err = format!("Failed to migrate data for {}: {}", username, e);
+                error!("{}", err);
+                err
+            })?;
+        }
+        info!("Migrated legacy account {} to id {}", username, account.id);
+        index.accounts.insert(lookup_key(&username), account);
+        migrated = true;
+    }
+    Ok(migrated)
+}
+
+/// Brings legacy account files into the index. Run once at startup, before
+/// any command reads the index.
+pub fn migrate(accounts_dir: &Path) -> Result<(), String> {
+    let _guard = INDEX_LOCK.lock().unwrap();
+    let mut index = load(accounts_dir)?;
+    if migrate_legacy(accounts_dir, &mut index)? {
+        store(accounts_dir, &index)?;
+    }
+    Ok(())
+}
+
+fn existing<'a>(index: &'a Index, accounts_dir: &Path, username: &str) -> Option<&'a AccountEntry> {
+    index.accounts.get(&lookup_key(username))
+        .filter(|entry| entry.keystore_path(accounts_dir).exists())
+}
+
+/// Finds an existing account by username.
+pub fn find(accounts_dir: &Path, username: &str) -> Result<AccountEntry, String> {
+    let username = normalize_username(username)?;
+    let _guard = INDEX_LOCK.lock().unwrap();
+    let index = load(accounts_dir)?;
+    existing(&index, accounts_dir, &username).cloned().ok_or_else(|| {
+        error!("Account does not exist: {}", username);
+        "Account does not exist".to_string()
+    })
+}
+
+/// Reserves a fresh id for a new account. An entry whose keystore was never
+/// written (for example after a failed creation) is replaced.
+pub fn claim(accounts_dir: &Path, username: &str) -> Result<AccountEntry, String> {
+    let username = normalize_username(username)?;
+    let _guard = INDEX_LOCK.lock().unwrap();
+    let mut index = load(accounts_dir)?;
+    if existing(&index, accounts_dir, &username).is_some() {
+        error!("Username already exists: {}", username);
+        return Err("Username already exists on this device.".to_string());
+    }
+    let account = AccountEntry { username: username.clone(), id: new_id() };
+    index.accounts.insert(lookup_key(&username), account.clone());
+    store(accounts_dir, &index)?;
+    debug!("Claimed id {} for {}", account.id, username);
+    Ok(account)
+}
+
+/// Points `new_username` at the existing account of `username`.
+pub fn rename_entry(accounts_dir: &Path, username: &str, new_username: &str) -> Result<AccountEntry, String> {
+    let username = normalize_username(username)?;
+    let new_username = normalize_username(new_username)?;
+    let _guard = INDEX_LOCK.lock().unwrap();
+    let mut index = load(accounts_dir)?;
+    let mut account = existing(&index, accounts_dir, &username).cloned().ok_or_else(|| {
+        error!("Account does not exist: {}", username);
+        "Account does not exist".to_string()
+    })?;
+    if lookup_key(&username) != lookup_key(&new_username) && existing(&index, accounts_dir, &new_username).is_some() {
+        error!("Username already exists: {}", new_username);
+        return Err("Username already exists on this device.".to_string());
+    }
+    index.accounts.remove(&lookup_key(&username));
+    account.username = new_username.clone();
+    index.accounts.insert(lookup_key(&new_username), account.clone());
+    store(accounts_dir, &index)?;
+    info!("Index entry {} renamed to {}", username, new_username);
+    Ok(account)
+}
+
+/// Drops an account from the index. Its files must be removed separately.
+pub fn remove(accounts_dir: &Path, username: &str) -> Result<(), String> {
+    let username = normalize_username(username)?;
+    let _guard = INDEX_LOCK.lock().unwrap();
+    let mut index = load(accounts_dir)?;
+    index.accounts.remove(&lookup_key(&username));
+    store(accounts_dir, &index)
+}
+
+/// Display usernames of every account with a keystore on disk.
+pub fn list(accounts_dir: &Path) -> Result<Vec<String>, String> {
+    let _guard = INDEX_LOCK.lock().unwrap();
+    let index = load(accounts_dir)?;
+    let mut usernames: Vec<String> = index.accounts.values()
+        .filter(|entry| entry.keystore_path(accounts_dir).exists())
+        .map(|entry| entry.username.clone())
+        .collect();
+    usernames.sort();
+    Ok(usernames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn usernames_are_normalized() {
+        assert_eq!(normalize_username("  \u{FF21}lice ").unwrap(), "Alice");
+        assert_eq!(normalize_username("bob.smith_2").unwrap(), "bob.smith_2");
+        for bad in ["", "   ", "a/b", "..", "-bob", "a  b", &"x".repeat(USERNAME_MAX_LEN + 1)] {
+            assert!(normalize_username(bad).is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn usernames_are_case_insensitive() {
+        let accounts_dir = std::env::temp_dir().join(format!("dumbchat-accounts-{}", new_id()));
+        create_dir_all(&accounts_dir).unwrap();
+        let account = claim(&accounts_dir, "Alice").unwrap();
+        assert!(is_id(&account.id));
+        write(account.keystore_path(&accounts_dir), b"keystore").unwrap();
+        assert!(claim(&accounts_dir, "alice").is_err());
+        assert_eq!(find(&accounts_dir, "ALICE").unwrap().id, account.id);
+        assert_eq!(list(&accounts_dir).unwrap(), vec!["Alice".to_string()]);
+        remove_dir_all(&accounts_dir).unwrap();
+    }
+
+    #[test]
+    fn load_is_read_only_until_migrated() {
+        let accounts_dir = std::env::temp_dir().join(format!("dumbchat-accounts-{}", new_id()));
+        create_dir_all(&accounts_dir).unwrap();
+        write(accounts_dir.join("Alice.enc"), b"keystore").unwrap();
+        assert!(find(&accounts_dir, "alice").is_err());
+        assert!(!accounts_dir.join(INDEX_FILE).exists());
+        migrate(&accounts_dir).unwrap();
+        let account = find(&accounts_dir, "alice").unwrap();
+        assert_eq!(account.username, "Alice");
+        assert!(account.keystore_path(&accounts_dir).exists());
+        assert!(!accounts_dir.join("Alice.enc").exists());
+        remove_dir_all(&accounts_dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, read};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, warn};
+
+use crate::keystore;
+
+pub const ATTEMPTS_FILE: &str = "login_attempts.json";
+
+/// Failures allowed in a row before delays start.
+const FREE_ATTEMPTS: u32 = 3;
+/// Delay after the first throttled failure; it doubles with each one after.
+const BASE_DELAY_SECS: u64 = 2;
+const MAX_DELAY_SECS: u64 = 15 * 60;
+/// Oldest entries are dropped past this, so a flood of guesses cannot grow
+/// the file without bound.
+const MAX_HISTORY: usize = 200;
+
+/// Held while the attempt record is read and written back, so concurrent
+/// unlocks cannot lose each other's updates. Never held across the key
+/// derivation itself.
+static ATTEMPTS_LOCK: Mutex<()> = Mutex::new(());
+
+/// One unlock attempt, as shown to the user after they log in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    /// Command that asked for the secret, such as `login` or `delete_account`.
+    pub action: String,
+    /// `password` or `recovery_key`.
+    pub method: String,
+    pub success: bool,
+    /// Why a failed attempt failed.
+    pub detail: Option<String>,
+}
+
+/// Failed-unlock counter and audit trail of one account, kept in its data
+/// directory.
+///
+/// This is a usability measure, not a brute-force control. The file is
+/// plain JSON that has to be written before any secret is known, so nothing
+/// can authenticate it: anyone who can write to the data directory resets
+/// it by editing or deleting it, and anyone who can read the keystore
+/// attacks it offline without the app. Only the Argon2 cost defends against
+/// guessing. The counter just slows down someone trying passwords at the
+/// unlock screen, and the history is informative, not evidence.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct LoginAttempts {
+    /// Failures since the last successful unlock.
+    pub failures: u32,
+    /// Unix time of the most recent failure.
+    pub last_failure: u64,
+    pub history: Vec<AuditEntry>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Serializes unlock attempts across commands.
+pub fn lock() -> MutexGuard<'static, ()> {
+    ATTEMPTS_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl LoginAttempts {
+    /// Loads the record of the account in `data_dir`. A file that does not
+    /// parse starts a new record rather than blocking every unlock, which
+    /// costs nothing given that deleting the file does the same.
+    pub fn load(data_dir: &Path) -> Result<Self, String> {
+        let path = data_dir.join(ATTEMPTS_FILE);
+        if !path.exists() {
+            return Ok(LoginAttempts::default());
+        }
+        let data = read(&path).map_err(|e| {
+            let err = format!("Failed to read login attempts: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        Ok(serde_json::from_slice(&data).unwrap_or_else(|e| {
+            error!("Login attempts file is corrupted, starting a new one: {}", e);
+            LoginAttempts::default()
+        }))
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result<(), String> {
+        create_dir_all(data_dir).map_err(|e| {
+            let err = format!("Failed to create {:?}: {}", data_dir, e);
+            error!("{}", err);
+            err
+        })?;
+        let data = serde_json::to_vec_pretty(self).map_err(|e| {
+            let err = format!("Login attempts serialization failed: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        keystore::save(&data_dir.join(ATTEMPTS_FILE), &data)
+    }
+
+    /// Delay imposed after `failures` consecutive failures.
+    fn delay(failures: u32) -> u64 {
+        if failures < FREE_ATTEMPTS {
+            return 0;
+        }
+        let doublings = (failures - FREE_ATTEMPTS).min(32);
+        BASE_DELAY_SECS.saturating_mul(1 << doublings).min(MAX_DELAY_SECS)
+    }
+
+    /// Seconds left before another attempt is allowed at time `now`.
+    pub fn wait_secs(&self, now: u64) -> u64 {
+        let delay = Self::delay(self.failures);
+        // Capped at the delay itself, so a clock set backwards cannot lock
+        // the account for longer than that.
+        (self.last_failure + delay).saturating_sub(now).min(delay)
+    }
+
+    /// Whether the password alone is no longer accepted. A `threshold` of 0
+    /// never requires the recovery key.
+    pub fn recovery_key_required(&self, threshold: u32) -> bool {
+        threshold > 0 && self.failures >= threshold
+    }
+
+    /// Counts an attempt as failed before its secret is checked, so attempts
+    /// started meanwhile are throttled by it and one that never finishes
+    /// still counts. `record` clears the count if it succeeds.
+    pub fn begin(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+        self.last_failure = now();
+    }
+
+    /// Adds an attempt to the history. A success clears the failure count;
+    /// failures were already counted by `begin`, or were refused without
+    /// checking the secret and do not count.
+    pub fn record(&mut self, action: &str, method: &str, outcome: Result<(), &str>) {
+        let timestamp = now();
+        match outcome {
+            Ok(()) => {
+                if self.failures > 0 {
+                    debug!("Clearing {} failed attempts", self.failures);
+                }
+                self.failures = 0;
+            }
+            Err(reason) => {
+                warn!("{} attempt via {} failed ({} in a row): {}", action, method, self.failures, reason);
+            }
+        }
+        self.history.push(AuditEntry {
+            timestamp,
+            action: action.to_string(),
+            method: method.to_string(),
+            success: outcome.is_ok(),
+            detail: outcome.err().map(str::to_string),
+        });
+        if self.history.len() > MAX_HISTORY {
+            let excess = self.history.len() - MAX_HISTORY;
+            self.history.drain(..excess);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupted_file_starts_a_new_record() {
+        let dir = std::env::temp_dir().join(format!("dumbchat-attempts-{}", hex::encode(rand::random::<[u8; 8]>())));
+        create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(ATTEMPTS_FILE), b"{\"failures\": 3,").unwrap();
+        let mut attempts = LoginAttempts::load(&dir).unwrap();
+        assert_eq!(attempts.failures, 0);
+        attempts.begin();
+        attempts.save(&dir).unwrap();
+        assert_eq!(LoginAttempts::load(&dir).unwrap().failures, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delays_grow_and_clear_on_success() {
+        let mut attempts = LoginAttempts::default();
+        for _ in 0..FREE_ATTEMPTS {
+            assert_eq!(attempts.wait_secs(now()), 0);
+            attempts.begin();
+            attempts.record("login", "password", Err("wrong password"));
+        }
+        let first = attempts.wait_secs(attempts.last_failure);
+        assert_eq!(first, BASE_DELAY_SECS);
+        attempts.begin();
+        attempts.record("login", "password", Err("wrong password"));
+        assert_eq!(attempts.wait_secs(attempts.last_failure), 2 * first);
+        // A clock set far back never waits longer than the delay itself.
+        assert_eq!(attempts.wait_secs(0), 2 * first);
+        // Refused attempts are logged but not counted.
+        attempts.record("login", "password", Err("throttled"));
+        assert_eq!(attempts.failures, FREE_ATTEMPTS + 1);
+        assert!(attempts.recovery_key_required(FREE_ATTEMPTS + 1));
+        assert!(!attempts.recovery_key_required(0));
+        attempts.begin();
+        attempts.record("login", "password", Ok(()));
+        assert_eq!(attempts.failures, 0);
+        assert_eq!(attempts.wait_secs(now()), 0);
+        assert_eq!(attempts.history.len(), FREE_ATTEMPTS as usize + 3);
+    }
+}
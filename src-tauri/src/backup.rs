@@ -0,0 +1,323 @@
+use aes_gcm::{aead::{Aead, AeadCore, KeyInit, OsRng}, Aes256Gcm, Nonce, Key};
+use argon2::password_hash::SaltString;
+use base64::{Engine as _, engine::general_purpose};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::symlink_metadata;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info};
+
+use crate::keystore::{self, KdfParams, Unlock};
+
+/// Magic bytes at the start of every account bundle.
+const MAGIC: &[u8; 4] = b"DCBK";
+const BUNDLE_VERSION: u8 = 2;
+
+const MODE_RECOVERY_KEY: u8 = 1;
+const MODE_PASSPHRASE: u8 = 2;
+
+const NONCE_LEN: usize = 12;
+
+/// HKDF info for the key of recovery-key bundles, so it never equals the
+/// recovery key itself or a key derived from it for another purpose.
+const RECOVERY_KEY_LABEL: &[u8] = b"dumbchat/backup/v1";
+
+/// Entry name of the keystore inside a bundle. Everything else comes from the
+/// account's data directory and is stored under `data/`.
+pub const KEYSTORE_ENTRY: &str = "keystore.enc";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub bundle_version: u8,
+    pub app_version: String,
+    pub username: String,
+    pub created_at: u64,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Contents {
+    manifest: Manifest,
+    files: BTreeMap<String, String>,
+}
+
+/// A decrypted bundle whose files have all been checked against the manifest.
+pub struct Bundle {
+    pub manifest: Manifest,
+    pub files: BTreeMap<String, Vec<u8>>,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn bundle_key(secret: &Unlock, kdf: KdfParams, salt: &[u8]) -> Result<[u8; 32], String> {
+    match secret {
+        Unlock::Password(passphrase) => keystore::derive_password_key(passphrase, salt, kdf),
+        Unlock::RecoveryKey(recovery_key) => {
+            if recovery_key.len() != 32 {
+                let err = "Invalid recovery key length".to_string();
+                error!("{}", err);
+                return Err(err);
+            }
+            let mut key = [0u8; 32];
+            Hkdf::<Sha256>::new(Some(salt), recovery_key).expand(RECOVERY_KEY_LABEL, &mut key).map_err(|e| {
+                let err = format!("Bundle key derivation failed: {}", e);
+                error!("{}", err);
+                err
+            })?;
+            Ok(key)
+        }
+    }
+}
+
+/// Packs `files` into an encrypted bundle for `username`.
+///
+/// The header is `MAGIC || version || mode`, followed for passphrase bundles
+/// by `m_cost || t_cost || p_cost`, then `salt_len || salt || nonce ||
+/// ciphertext`. The salt feeds Argon2 for a passphrase and HKDF for the
+/// recovery key. The plaintext is a JSON manifest listing every file with its
+/// size and SHA-256, plus the base64 file contents.
+pub fn seal(username: &str, files: BTreeMap<String, Vec<u8>>, secret: Unlock) -> Result<Vec<u8>, String> {
+    let manifest = Manifest {
+        bundle_version: BUNDLE_VERSION,
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        username: username.to_string(),
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+        files: files.iter().map(|(name, data)| ManifestEntry {
+            name: name.clone(),
+            size: data.len() as u64,
+            sha256: sha256_hex(data),
+        }).collect(),
+    };
+    debug!("Bundle manifest: {:?}", manifest);
+    let contents = Contents {
+        manifest,
+        files: files.iter().map(|(name, data)| (name.clone(), general_purpose::STANDARD.encode(data))).collect(),
+    };
+    let mut plaintext = serde_json::to_vec(&contents).map_err(|e| {
+        let err = format!("Bundle serialization failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.push(BUNDLE_VERSION);
+    let kdf = KdfParams::calibrated();
+    let salt = SaltString::generate(&mut OsRng);
+    let salt = salt.as_ref().as_bytes();
+    match secret {
+        Unlock::Password(_) => {
+            out.push(MODE_PASSPHRASE);
+            out.extend_from_slice(&kdf.m_cost.to_be_bytes());
+            out.extend_from_slice(&kdf.t_cost.to_be_bytes());
+            out.extend_from_slice(&kdf.p_cost.to_be_bytes());
+        }
+        Unlock::RecoveryKey(_) => out.push(MODE_RECOVERY_KEY),
+    }
+    out.extend_from_slice(&(salt.len() as u16).to_be_bytes());
+    out.extend_from_slice(salt);
+    let mut key = bundle_key(&secret, kdf, salt)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    key.fill(0);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    // The header is authenticated too, so the mode and KDF settings cannot be
+    // altered without the bundle failing to open.
+    let ciphertext = cipher.encrypt(&nonce, aes_gcm::aead::Payload { msg: &plaintext, aad: &out });
+    plaintext.fill(0);
+    let ciphertext = ciphertext.map_err(|e| {
+        let err = format!("Bundle encryption failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    out.extend_from_slice(nonce.as_slice());
+    out.extend_from_slice(&ciphertext);
+    info!("Bundle sealed for {}, {} bytes", username, out.len());
+    Ok(out)
+}
+
+/// Checks where an export may be written and returns the resolved path.
+///
+/// The destination comes straight from the frontend, so it must be an
+/// absolute path to a file in an existing directory. It may not be a
+/// directory or a link, and may not lie inside `app_data`, where a bundle
+/// could overwrite a keystore or store.
+pub fn destination(destination: &str, app_data: &Path) -> Result<PathBuf, String> {
+    let refused = |msg: &str| {
+        let err = format!("Invalid export destination: {}", msg);
+        error!("{}", err);
+        err
+    };
+    let path = Path::new(destination.trim());
+    if !path.is_absolute() {
+        return Err(refused("the path must be absolute"));
+    }
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(refused("the path must name a file"));
+    };
+    let parent = parent.canonicalize().map_err(|e| refused(&format!("{:?} cannot be opened: {}", parent, e)))?;
+    if !parent.is_dir() {
+        return Err(refused(&format!("{:?} is not a directory", parent)));
+    }
+    let resolved = parent.join(name);
+    if let Ok(metadata) = symlink_metadata(&resolved) {
+        if !metadata.is_file() {
+            return Err(refused("the path is a directory or a link"));
+        }
+    }
+    if app_data.canonicalize().is_ok_and(|app_data| resolved.starts_with(app_data)) {
+        return Err(refused("the path is inside the app's own data"));
+    }
+    Ok(resolved)
+}
+
+/// Decrypts a bundle and verifies every file against the manifest.
+pub fn open(data: &[u8], secret: Unlock) -> Result<Bundle, String> {
+    let invalid = |msg: &str| {
+        error!("Bundle open failed: {}", msg);
+        format!("Invalid account bundle: {}", msg)
+    };
+    if !data.starts_with(MAGIC) || data.len() < MAGIC.len() + 2 {
+        return Err(invalid("not a DumbChat account bundle"));
+    }
+    let version = data[4];
+    if version != BUNDLE_VERSION {
+        return Err(invalid(&format!("unsupported version {}", version)));
+    }
+    let mode = data[5];
+    let mut pos = 6;
+    let mut kdf = KdfParams::default();
+    match (mode, &secret) {
+        (MODE_PASSPHRASE, Unlock::Password(_)) => {
+            if data.len() < pos + 12 {
+                return Err(invalid("truncated header"));
+            }
+            let u32_at = |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+            kdf = KdfParams { m_cost: u32_at(pos), t_cost: u32_at(pos + 4), p_cost: u32_at(pos + 8) };
+            if !kdf.within_limits() {
+                return Err(invalid("KDF parameters out of range"));
+            }
+            pos += 12;
+        }
+        (MODE_RECOVERY_KEY, Unlock::RecoveryKey(_)) => {}
+        (MODE_PASSPHRASE, _) => return Err(invalid("this bundle is protected by a passphrase")),
+        (MODE_RECOVERY_KEY, _) => return Err(invalid("this bundle is protected by the recovery key")),
+        (other, _) => return Err(invalid(&format!("unknown protection mode {}", other))),
+    }
+    if data.len() < pos + 2 {
+        return Err(invalid("truncated header"));
+    }
+    let salt_len = u16::from_be_bytes([data[pos], data[pos + 1]]) as usize;
+    pos += 2;
+    if data.len() < pos + salt_len {
+        return Err(invalid("truncated header"));
+    }
+    let salt = &data[pos..pos + salt_len];
+    pos += salt_len;
+    if data.len() < pos + NONCE_LEN {
+        return Err(invalid("truncated header"));
+    }
+    let header = &data[..pos];
+    let nonce = Nonce::from_slice(&data[pos..pos + NONCE_LEN]);
+    let ciphertext = &data[pos + NONCE_LEN..];
+    let mut key = bundle_key(&secret, kdf, salt)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    key.fill(0);
+    let mut plaintext = cipher.decrypt(nonce, aes_gcm::aead::Payload { msg: ciphertext, aad: header })
+        .map_err(|_| invalid("wrong secret or corrupted file"))?;
+    let contents: Result<Contents, _> = serde_json::from_slice(&plaintext);
+    plaintext.fill(0);
+    let contents = contents.map_err(|e| invalid(&format!("malformed contents: {}", e)))?;
+    let mut files = BTreeMap::new();
+    for entry in &contents.manifest.files {
+        let encoded = contents.files.get(&entry.name)
+            .ok_or_else(|| invalid(&format!("{} listed in manifest but missing", entry.name)))?;
+        let bytes = general_purpose::STANDARD.decode(encoded)
+            .map_err(|e| invalid(&format!("{} is not valid base64: {}", entry.name, e)))?;
+        if bytes.len() as u64 != entry.size || sha256_hex(&bytes) != entry.sha256 {
+            return Err(invalid(&format!("{} failed its integrity check", entry.name)));
+        }
+        files.insert(entry.name.clone(), bytes);
+    }
+    if files.len() != contents.files.len() {
+        return Err(invalid("contains files not listed in the manifest"));
+    }
+    if !files.contains_key(KEYSTORE_ENTRY) {
+        return Err(invalid("no keystore inside"));
+    }
+    info!("Bundle opened for {} with {} files", contents.manifest.username, files.len());
+    Ok(Bundle { manifest: contents.manifest, files })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn files() -> BTreeMap<String, Vec<u8>> {
+        BTreeMap::from([
+            (KEYSTORE_ENTRY.to_string(), b"keystore".to_vec()),
+            ("data/contacts.json".to_string(), b"{}".to_vec()),
+        ])
+    }
+
+    #[test]
+    fn recovery_key_bundle_roundtrip() {
+        let recovery_key = [7u8; 32];
+        let bundle = seal("alice", files(), Unlock::RecoveryKey(&recovery_key)).unwrap();
+        let opened = open(&bundle, Unlock::RecoveryKey(&recovery_key)).unwrap();
+        assert_eq!(opened.manifest.username, "alice");
+        assert_eq!(opened.files, files());
+        assert!(open(&bundle, Unlock::RecoveryKey(&[8u8; 32])).is_err());
+    }
+
+    #[test]
+    fn recovery_key_is_not_the_bundle_key() {
+        let recovery_key = [7u8; 32];
+        let bundle = seal("alice", files(), Unlock::RecoveryKey(&recovery_key)).unwrap();
+        // MAGIC || version || mode || salt_len || salt || nonce || ciphertext
+        let nonce_at = 8 + u16::from_be_bytes([bundle[6], bundle[7]]) as usize;
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&recovery_key));
+        let raw = cipher.decrypt(Nonce::from_slice(&bundle[nonce_at..nonce_at + NONCE_LEN]), aes_gcm::aead::Payload {
+            msg: &bundle[nonce_at + NONCE_LEN..],
+            aad: &bundle[..nonce_at],
+        });
+        assert!(raw.is_err());
+    }
+
+    #[test]
+    fn passphrase_bundle_rejects_recovery_key() {
+        let bundle = seal("alice", files(), Unlock::Password("correct horse")).unwrap();
+        assert!(open(&bundle, Unlock::Password("correct horse")).is_ok());
+        assert!(open(&bundle, Unlock::Password("wrong horse")).is_err());
+        assert!(open(&bundle, Unlock::RecoveryKey(&[7u8; 32])).is_err());
+    }
+
+    #[test]
+    fn destination_must_be_a_file_outside_app_data() {
+        let mut id = [0u8; 8];
+        OsRng.fill_bytes(&mut id);
+        let root = std::env::temp_dir().join(format!("dumbchat-backup-{}", hex::encode(id)));
+        let (app_data, elsewhere) = (root.join("app"), root.join("exports"));
+        std::fs::create_dir_all(app_data.join("accounts")).unwrap();
+        std::fs::create_dir_all(&elsewhere).unwrap();
+        let target = elsewhere.join("alice.dcbk");
+        let resolved = destination(target.to_str().unwrap(), &app_data).unwrap();
+        assert_eq!(resolved, elsewhere.canonicalize().unwrap().join("alice.dcbk"));
+        assert!(destination("alice.dcbk", &app_data).is_err());
+        assert!(destination(elsewhere.to_str().unwrap(), &app_data).is_err());
+        assert!(destination(root.join("missing/alice.dcbk").to_str().unwrap(), &app_data).is_err());
+        assert!(destination(app_data.join("accounts/alice.enc").to_str().unwrap(), &app_data).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
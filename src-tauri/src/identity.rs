@@ -0,0 +1,179 @@
+use bip39::Mnemonic;
+use ed25519_dalek::SigningKey;
+use hkdf::Hkdf;
+use nostr_sdk::{FromBech32, Keys, SecretKey, ToBech32};
+use nostr_sdk::nips::nip49::{EncryptedSecretKey, KeySecurity};
+use rand::{RngCore, rngs::OsRng};
+use sha2::Sha256;
+use x25519_dalek::StaticSecret;
+use tracing::{debug, error};
+
+/// HKDF salt for identity derivation. Bumping it changes every derived key.
+const IDENTITY_SALT: &[u8] = b"dumbchat/identity/v1";
+/// scrypt cost for NIP-49 exports: 2^16 rounds, 64 MiB, as NIP-49 suggests
+/// for interactive use.
+const NCRYPTSEC_LOG_N: u8 = 16;
+
+/// Every long-term key of an account, derived from one master seed.
+pub struct Identity {
+    pub ed25519: SigningKey,
+    pub x25519: StaticSecret,
+    pub nostr: Keys,
+}
+
+/// Generates a fresh 24-word BIP-39 mnemonic from 256 bits of entropy.
+pub fn generate_mnemonic() -> Result<Mnemonic, String> {
+    let mut entropy = [0u8; 32];
+    OsRng.fill_bytes(&mut entropy);
+    let mnemonic = Mnemonic::from_entropy(&entropy).map_err(|e| {
+        let err = format!("Mnemonic generation failed: {}", e);
+        error!("{}", err);
+        err
+    });
+    entropy.fill(0);
+    mnemonic
+}
+
+/// Parses a user-entered word list, tolerating extra whitespace and case.
+pub fn parse_mnemonic(words: &str) -> Result<Mnemonic, String> {
+    let normalized = words.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    Mnemonic::parse(normalized).map_err(|e| {
+        let err = format!("Invalid recovery phrase: {}", e);
+        error!("{}", err);
+        err
+    })
+}
+
+/// Derives the ed25519, X25519 and Nostr keys from the mnemonic's BIP-39 seed.
+///
+/// Each key is expanded with HKDF-SHA256 under its own `info` label, so no
+/// two keys share output and new key types can be added without touching the
+/// existing ones.
+pub fn derive(mnemonic: &Mnemonic) -> Result<Identity, String> {
+    let mut seed = mnemonic.to_seed("");
+    let hk = Hkdf::<Sha256>::new(Some(IDENTITY_SALT), &seed);
+    seed.fill(0);
+    let mut okm = [0u8; 32];
+    expand(&hk, b"ed25519", &mut okm)?;
+    let ed25519 = SigningKey::from_bytes(&okm);
+    expand(&hk, b"x25519", &mut okm)?;
+    let x25519 = StaticSecret::from(okm);
+    // A secp256k1 scalar must be non-zero and below the group order. HKDF
+    // output misses that range with negligible probability, but retry with a
+    // counter rather than fail.
+    let mut nostr_secret = None;
+    for counter in 0u8..=255 {
+        let info = [b"nostr-secp256k1".as_slice(), &[counter]].concat();
+        expand(&hk, &info, &mut okm)?;
+        if let Ok(secret) = SecretKey::from_slice(&okm) {
+            nostr_secret = Some(secret);
+            break;
+        }
+    }
+    okm.fill(0);
+    let nostr_secret = nostr_secret.ok_or_else(|| {
+        let err = "Nostr key derivation failed".to_string();
+        error!("{}", err);
+        err
+    })?;
+    debug!("Identity keys derived from master seed");
+    Ok(Identity { ed25519, x25519, nostr: Keys::new(nostr_secret) })
+}
+
+/// Parses an existing Nostr secret key given as `nsec1...`, 64 hex chars, or
+/// a NIP-49 `ncryptsec1...` string together with its passphrase.
+pub fn parse_nostr_secret(secret: &str, passphrase: Option<&str>) -> Result<Keys, String> {
+    let secret = secret.trim();
+    if secret.starts_with("ncryptsec1") {
+        return decrypt_ncryptsec(secret, passphrase.unwrap_or_default());
+    }
+    let is_hex = secret.len() == 64 && secret.chars().all(|c| c.is_ascii_hexdigit());
+    if !(secret.starts_with("nsec1") || is_hex) {
+        error!("Nostr secret import failed: not an nsec, ncryptsec or 64-char hex key");
+        return Err("Nostr secret must be an nsec1..., ncryptsec1... string or 64 hex characters".to_string());
+    }
+    Keys::parse(secret).map_err(|e| {
+        let err = format!("Invalid Nostr secret key: {}", e);
+        error!("{}", err);
+        err
+    })
+}
+
+/// Encrypts the Nostr secret key as a NIP-49 `ncryptsec1...` string.
+pub fn encrypt_ncryptsec(keys: &Keys, passphrase: &str) -> Result<String, String> {
+    let encrypted = EncryptedSecretKey::new(keys.secret_key(), passphrase, NCRYPTSEC_LOG_N, KeySecurity::Unknown)
+        .map_err(|e| {
+            let err = format!("NIP-49 encryption failed: {}", e);
+            error!("{}", err);
+            err
+        })?;
+    encrypted.to_bech32().map_err(|e| {
+        let err = format!("Bech32 encode failed: {}", e);
+        error!("{}", err);
+        err
+    })
+}
+
+fn decrypt_ncryptsec(ncryptsec: &str, passphrase: &str) -> Result<Keys, String> {
+    if passphrase.is_empty() {
+        error!("NIP-49 import failed: passphrase missing");
+        return Err("A passphrase is required to import an ncryptsec key".to_string());
+    }
+    let encrypted = EncryptedSecretKey::from_bech32(ncryptsec).map_err(|e| {
+        let err = format!("Invalid ncryptsec: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    debug!("Decrypting ncryptsec with log_n {}", encrypted.log_n());
+    let secret_key = encrypted.to_secret_key(passphrase).map_err(|e| {
+        let err = format!("NIP-49 decryption failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    Ok(Keys::new(secret_key))
+}
+
+fn expand(hk: &Hkdf<Sha256>, label: &[u8], okm: &mut [u8]) -> Result<(), String> {
+    let info = [b"dumbchat/".as_slice(), label].concat();
+    hk.expand(&info, okm).map_err(|e| {
+        let err = format!("Key derivation failed: {}", e);
+        error!("{}", err);
+        err
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phrase_rebuilds_the_same_identity() {
+        let mnemonic = generate_mnemonic().unwrap();
+        let original = derive(&mnemonic).unwrap();
+        // Extra whitespace and capitals, as a user might type it back in.
+        let typed = format!("  {}\n", mnemonic.to_string().to_uppercase().replace(' ', "  "));
+        let restored = derive(&parse_mnemonic(&typed).unwrap()).unwrap();
+        assert_eq!(original.ed25519.to_bytes(), restored.ed25519.to_bytes());
+        assert_eq!(original.x25519.to_bytes(), restored.x25519.to_bytes());
+        assert_eq!(original.nostr.public_key(), restored.nostr.public_key());
+        let other = derive(&generate_mnemonic().unwrap()).unwrap();
+        assert_ne!(original.nostr.public_key(), other.nostr.public_key());
+    }
+
+    #[test]
+    fn bad_checksum_is_refused() {
+        let valid = format!("{} art", ["abandon"; 23].join(" "));
+        assert!(parse_mnemonic(&valid).is_ok());
+        assert!(parse_mnemonic(&["abandon"; 24].join(" ")).is_err());
+    }
+
+    #[test]
+    fn ncryptsec_roundtrip() {
+        let keys = Keys::generate();
+        let ncryptsec = encrypt_ncryptsec(&keys, "passphrase").unwrap();
+        assert!(ncryptsec.starts_with("ncryptsec1"));
+        let imported = parse_nostr_secret(&ncryptsec, Some("passphrase")).unwrap();
+        assert_eq!(imported.public_key(), keys.public_key());
+        assert!(parse_nostr_secret(&ncryptsec, None).is_err());
+    }
+}
@@ -0,0 +1,1182 @@
+use aes_gcm::{aead::{Aead, AeadCore, KeyInit, OsRng, Payload}, Aes256Gcm, Nonce, Key};
+use argon2::{Argon2, Algorithm, Version, Params};
+use argon2::password_hash::SaltString;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::fs::{remove_file, rename, write, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info};
+use zeroize::{Zeroize, Zeroizing};
+
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const WRAPPED_KEY_LEN: usize = KEY_LEN + TAG_LEN;
+/// Size every v3 payload is padded to before encryption, so the real and the
+/// decoy payload, and a file with or without a decoy, are the same length.
+const PAYLOAD_LEN: usize = 2048;
+/// `role || flags || decoy_key || len` in front of the padded `LoginData`.
+const ENVELOPE_HEADER_LEN: usize = 2 + KEY_LEN + 2;
+/// Length of the tags binding a v3 primary payload to the username.
+const BINDING_LEN: usize = 32;
+/// HKDF `info` for the key of the primary's own binding tag.
+const BINDING_LABEL: &[u8] = b"dumbchat/keystore/binding";
+/// HKDF `info` for the key of the tag a decoy writes when it renames the
+/// account.
+const RENAME_LABEL: &[u8] = b"dumbchat/keystore/rename";
+
+const ROLE_PRIMARY: u8 = 1;
+const ROLE_DECOY: u8 = 2;
+/// Set on a decoy whose first unlock should destroy the primary.
+const FLAG_WIPE_PRIMARY: u8 = 1;
+/// Set on a primary that carries the decoy's data key.
+const FLAG_HAS_DECOY: u8 = 2;
+
+/// Magic bytes at the start of every versioned keystore file.
+const MAGIC: &[u8; 4] = b"DCKS";
+/// Version written by `seal`. Files with an older layout are still read and
+/// rewritten in this version on the next successful unlock.
+///
+/// Version 3 gives every file a duress slot and a decoy payload, filled
+/// with random bytes when unused, and binds the primary payload to the
+/// username with a tag rather than associated data. Version 2 authenticates
+/// the header and the account's username as associated data of the payload;
+/// version 1 used no associated data.
+pub const FORMAT_VERSION: u8 = 3;
+
+const KDF_ARGON2ID: u8 = 1;
+
+/// Unlock time that new keystores are calibrated to.
+const TARGET_UNLOCK_TIME: Duration = Duration::from_secs(1);
+/// Ceiling for calibrated memory, so a keystore made on a fast desktop still
+/// opens on a small laptop. 512 MiB, in KiB as Argon2 counts it.
+const MAX_CALIBRATED_M_COST: u32 = 512 * 1024;
+const MAX_CALIBRATED_T_COST: u32 = 16;
+/// Limits on parameters read from a file, so a doctored header cannot exhaust
+/// memory or time. Memory is held to the calibration ceiling, as no keystore
+/// this app writes ever asks for more.
+const MAX_M_COST: u32 = MAX_CALIBRATED_M_COST;
+const MAX_T_COST: u32 = 64;
+const MAX_P_COST: u32 = 16;
+
+static CALIBRATED: OnceLock<KdfParams> = OnceLock::new();
+
+/// Argon2id cost parameters, stored in the file so they can be raised for
+/// new keystores without breaking existing ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    /// Parameters used by every file written before the format was versioned.
+    /// Also the floor for calibration.
+    const LEGACY: KdfParams = KdfParams { m_cost: 65536, t_cost: 2, p_cost: 1 };
+
+    /// Parameters for new keystores, tuned so an unlock takes about
+    /// `TARGET_UNLOCK_TIME` on this machine. Measured once per run.
+    pub fn calibrated() -> KdfParams {
+        // Tests exercise the format, not the cost.
+        if cfg!(test) {
+            return KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+        }
+        *CALIBRATED.get_or_init(calibrate)
+    }
+
+    /// Whether parameters read from a file are sane enough to run.
+    pub fn within_limits(&self) -> bool {
+        (1..=MAX_M_COST).contains(&self.m_cost)
+            && (1..=MAX_T_COST).contains(&self.t_cost)
+            && (1..=MAX_P_COST).contains(&self.p_cost)
+    }
+}
+
+/// Times a single pass over the legacy memory size, then spends the rest of
+/// the time budget on memory first, since that is what makes GPU guessing
+/// expensive, and on extra passes after that. Never goes below `LEGACY`.
+fn calibrate() -> KdfParams {
+    let probe = KdfParams { t_cost: 1, ..KdfParams::LEGACY };
+    let start = Instant::now();
+    if let Err(e) = derive_password_key("calibration", &[0u8; 16], probe) {
+        error!("Argon2 calibration failed, using legacy parameters: {}", e);
+        return KdfParams::LEGACY;
+    }
+    let pass = start.elapsed().max(Duration::from_millis(1));
+    let mut passes = TARGET_UNLOCK_TIME.as_secs_f64() / pass.as_secs_f64();
+    let mut m_cost = KdfParams::LEGACY.m_cost;
+    while passes >= 2.0 * KdfParams::LEGACY.t_cost as f64 && m_cost < MAX_CALIBRATED_M_COST {
+        m_cost *= 2;
+        passes /= 2.0;
+    }
+    let t_cost = (passes as u32).clamp(KdfParams::LEGACY.t_cost, MAX_CALIBRATED_T_COST);
+    let kdf = KdfParams { m_cost, t_cost, p_cost: KdfParams::LEGACY.p_cost };
+    info!("Argon2 calibrated to {:?}, one 64 MiB pass took {:?}", kdf, pass);
+    kdf
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams::LEGACY
+    }
+}
+
+/// Secret used to open a keystore: either the account password or the
+/// 32-byte recovery key handed out at account creation.
+pub enum Unlock<'a> {
+    Password(&'a str),
+    RecoveryKey(&'a [u8]),
+}
+
+/// Which of the two accounts in a keystore was opened.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Primary,
+    /// The account behind the duress password.
+    Decoy,
+}
+
+/// Layouts `open` understands. Only `Versioned(FORMAT_VERSION)` is ever written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// `salt_len || salt || nonce || ciphertext`, encrypted directly under the
+    /// password key. There is no recovery slot.
+    LegacySingleKey,
+    /// `salt_len || salt || pw_nonce || pw_wrapped || rk_nonce || rk_wrapped || nonce || ciphertext`.
+    LegacyEnvelope,
+    /// `MAGIC || version || kdf_id || m_cost || t_cost || p_cost || salt_len || salt ||
+    /// pw_nonce || pw_wrapped || rk_nonce || rk_wrapped || nonce || ciphertext`.
+    /// From version 2 the payload's associated data is everything up to and
+    /// including the salt, followed by `username_len || username`.
+    ///
+    /// Version 3 adds `duress_nonce || duress_wrapped` after the password slot
+    /// and two payloads, primary then decoy, each sealing an `Envelope`
+    /// padded to `PAYLOAD_LEN` bytes. It ends in `binding || rename`, the
+    /// tags binding the primary to the username, see `binding_mac`.
+    Versioned(u8),
+}
+
+#[derive(Clone)]
+struct Slot {
+    nonce: Vec<u8>,
+    wrapped: Vec<u8>,
+}
+
+impl Slot {
+    /// A slot of random bytes, standing in for an unused wrap or payload.
+    fn random(len: usize) -> Self {
+        let mut nonce = vec![0u8; NONCE_LEN];
+        let mut wrapped = vec![0u8; len];
+        OsRng.fill_bytes(&mut nonce);
+        OsRng.fill_bytes(&mut wrapped);
+        Slot { nonce, wrapped }
+    }
+}
+
+#[derive(Clone)]
+struct PasswordSlot {
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    slot: Slot,
+}
+
+/// Envelope-encrypted account file.
+///
+/// `LoginData` is encrypted under a random data key, and that data key is
+/// wrapped twice: once under the Argon2 key derived from the password and
+/// once under the recovery key. Either secret can therefore open the file.
+///
+/// From version 3 the file also has room for a decoy account, whose own data
+/// key is wrapped under a duress password derived with the same salt and
+/// cost. Without a decoy that room holds random bytes, which cannot be told
+/// apart from a wrap and a ciphertext without the duress password.
+struct Keystore {
+    format: Format,
+    password: PasswordSlot,
+    duress: Option<Slot>,
+    recovery: Option<Slot>,
+    payload: Slot,
+    decoy_payload: Option<Slot>,
+    /// From version 3, the primary's tag over the username.
+    binding: Option<Vec<u8>>,
+    /// From version 3, the decoy's tag over a new username and `binding`,
+    /// left by a rename done from the decoy. Random bytes otherwise.
+    rename: Option<Vec<u8>>,
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// MAC binding a v3 payload to `username`, keyed from a payload's data key.
+///
+/// The primary payload is not bound through its associated data, because a
+/// decoy that renames the account cannot re-encrypt it. Instead the primary
+/// tags the username with its own data key, and a decoy rename leaves a
+/// second tag, under the decoy's data key, over the new username and the
+/// primary's tag. The primary holds the decoy's data key and accepts either.
+fn binding_mac(key: &[u8], label: &[u8], header: &[u8], username: &str, extra: &[u8]) -> Hmac<Sha256> {
+    let mut mac_key = Zeroizing::new([0u8; KEY_LEN]);
+    Hkdf::<Sha256>::new(None, key)
+        .expand(label, mac_key.as_mut_slice())
+        .expect("32 bytes is a valid HKDF output length");
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key.as_slice()).expect("HMAC takes keys of any length");
+    mac.update(header);
+    mac.update(&(username.len() as u16).to_be_bytes());
+    mac.update(username.as_bytes());
+    mac.update(extra);
+    mac
+}
+
+/// Plaintext of a v3 payload: `role || flags || decoy_key || len || data`,
+/// zero-padded to `PAYLOAD_LEN`. The primary carries the decoy's data key
+/// so it can rewrite the decoy along with itself.
+struct Envelope {
+    role: u8,
+    flags: u8,
+    decoy_key: [u8; KEY_LEN],
+    plaintext: Vec<u8>,
+}
+
+impl Envelope {
+    /// Wraps plaintext from a file that predates envelopes.
+    fn legacy(plaintext: Vec<u8>) -> Self {
+        Envelope { role: ROLE_PRIMARY, flags: 0, decoy_key: [0u8; KEY_LEN], plaintext }
+    }
+
+    fn pad(role: u8, flags: u8, decoy_key: Option<&[u8; KEY_LEN]>, plaintext: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+        if plaintext.len() > PAYLOAD_LEN - ENVELOPE_HEADER_LEN {
+            let err = format!("Account data too large for keystore: {} bytes", plaintext.len());
+            error!("{}", err);
+            return Err(err);
+        }
+        // Allocated at full size up front, so nothing is left behind by a
+        // reallocation.
+        let mut out = Zeroizing::new(Vec::with_capacity(PAYLOAD_LEN));
+        out.push(role);
+        out.push(flags);
+        out.extend_from_slice(decoy_key.unwrap_or(&[0u8; KEY_LEN]));
+        out.extend_from_slice(&(plaintext.len() as u16).to_be_bytes());
+        out.extend_from_slice(plaintext);
+        out.resize(PAYLOAD_LEN, 0);
+        Ok(out)
+    }
+
+    fn unpad(padded: &[u8]) -> Option<Self> {
+        if padded.len() != PAYLOAD_LEN {
+            return None;
+        }
+        let len = u16::from_be_bytes([padded[2 + KEY_LEN], padded[3 + KEY_LEN]]) as usize;
+        let data = padded[ENVELOPE_HEADER_LEN..].get(..len)?;
+        let mut decoy_key = [0u8; KEY_LEN];
+        decoy_key.copy_from_slice(&padded[2..2 + KEY_LEN]);
+        Some(Envelope { role: padded[0], flags: padded[1], decoy_key, plaintext: data.to_vec() })
+    }
+}
+
+impl Drop for Envelope {
+    fn drop(&mut self) {
+        self.decoy_key.zeroize();
+        self.plaintext.zeroize();
+    }
+}
+
+/// The decoy as seen by an unlocked primary.
+struct Decoy {
+    data_key: [u8; KEY_LEN],
+    plaintext: Vec<u8>,
+    wipe_primary: bool,
+}
+
+impl Drop for Decoy {
+    fn drop(&mut self) {
+        self.data_key.zeroize();
+        self.plaintext.zeroize();
+    }
+}
+
+/// A successfully opened keystore. Keeps the data key and the existing wraps
+/// so the file can be rewritten without asking for every secret again.
+///
+/// Whichever account was opened, the other one's wrap and payload are kept
+/// as they are, so rewriting the file never disturbs an account the caller
+/// cannot see.
+pub struct Unlocked {
+    pub plaintext: Vec<u8>,
+    format: Format,
+    role: Role,
+    data_key: [u8; KEY_LEN],
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    password: Option<Slot>,
+    duress: Option<Slot>,
+    recovery: Option<Slot>,
+    /// The primary's payload and binding tag as stored, for a decoy to carry
+    /// over.
+    primary_payload: Option<Slot>,
+    primary_binding: Option<Vec<u8>>,
+    /// Opened as the primary through a decoy's rename tag, so the primary's
+    /// own binding and `LoginData` still carry the old username.
+    renamed: bool,
+    /// Opened as the primary: the decoy, if one is set up.
+    decoy: Option<Decoy>,
+    /// Opened as the decoy: whether the primary should be destroyed.
+    wipe_primary: bool,
+}
+
+impl Unlocked {
+    /// Whether the file should be rewritten: it is in an older layout, or it
+    /// was renamed from the decoy and the primary has not caught up yet.
+    pub fn outdated(&self) -> bool {
+        self.format != Format::Versioned(FORMAT_VERSION) || self.renamed
+    }
+
+    /// Whether the keystore itself authenticated the username it was opened
+    /// for. Older files rely on the username inside `LoginData` instead.
+    pub fn username_bound(&self) -> bool {
+        matches!(self.format, Format::Versioned(version) if version >= 2)
+    }
+
+    /// Whether the file can be opened with a recovery key.
+    pub fn has_recovery(&self) -> bool {
+        self.recovery.is_some()
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Whether a duress password is set up. Only the primary can tell.
+    pub fn has_decoy(&self) -> bool {
+        self.decoy.is_some()
+    }
+
+    /// Whether this duress unlock asked for the primary to be destroyed,
+    /// which happens the next time the file is rewritten.
+    pub fn wipe_pending(&self) -> bool {
+        self.role == Role::Decoy && self.wipe_primary
+    }
+
+    /// Sets up, or replaces, the decoy account that `duress_password` opens.
+    /// With `wipe_primary`, the first unlock through the duress password also
+    /// destroys this account. Only the primary can do this.
+    pub fn set_decoy(&mut self, duress_password: &str, plaintext: &[u8], wipe_primary: bool) -> Result<(), String> {
+        if self.role != Role::Primary {
+            let err = "Decryption failed: wrong password or corrupted keystore".to_string();
+            error!("{}", err);
+            return Err(err);
+        }
+        let mut data_key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut data_key);
+        let duress = self.wrap_password(duress_password, &data_key, self.password.as_ref());
+        let duress = match duress {
+            Ok(duress) => duress,
+            Err(e) => {
+                data_key.zeroize();
+                return Err(e);
+            }
+        };
+        self.duress = Some(duress);
+        self.decoy = Some(Decoy { data_key, plaintext: plaintext.to_vec(), wipe_primary });
+        data_key.zeroize();
+        Ok(())
+    }
+
+    /// Removes the decoy. Its wrap and payload go back to random bytes.
+    pub fn clear_decoy(&mut self) {
+        self.decoy = None;
+        self.duress = None;
+    }
+
+    /// Wraps `data_key` under `password` with this file's salt and cost,
+    /// refusing a password that already opens `other`, the other account's
+    /// slot, since only one of the two could ever be reached with it.
+    fn wrap_password(&self, password: &str, data_key: &[u8; KEY_LEN], other: Option<&Slot>) -> Result<Slot, String> {
+        let mut password_key = derive_password_key(password, &self.salt, self.kdf)?;
+        let slot = if let Some(mut key) = other.and_then(|slot| unwrap_key(&password_key, slot)) {
+            key.zeroize();
+            let err = "This password cannot be used for this account".to_string();
+            error!("{}", err);
+            Err(err)
+        } else {
+            encrypt(&password_key, data_key, &[])
+        };
+        password_key.zeroize();
+        slot
+    }
+}
+
+impl Drop for Unlocked {
+    fn drop(&mut self) {
+        self.data_key.zeroize();
+        self.plaintext.zeroize();
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            error!("Keystore truncated: wanted {} bytes, {} left", len, self.data.len());
+            return Err("Invalid encrypted data format".to_string());
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn slot(&mut self, len: usize) -> Result<Slot, String> {
+        Ok(Slot { nonce: self.take(NONCE_LEN)?.to_vec(), wrapped: self.take(len)?.to_vec() })
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+}
+
+impl Keystore {
+    fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.starts_with(MAGIC) {
+            Self::parse_versioned(&data[MAGIC.len()..])
+        } else {
+            Self::parse_legacy(data)
+        }
+    }
+
+    fn parse_versioned(data: &[u8]) -> Result<Self, String> {
+        let mut r = Reader { data };
+        let version = r.u8()?;
+        if version == 0 || version > FORMAT_VERSION {
+            error!("Unsupported keystore version: {}", version);
+            return Err(format!("Unsupported keystore version {}", version));
+        }
+        let kdf_id = r.u8()?;
+        if kdf_id != KDF_ARGON2ID {
+            error!("Unsupported keystore KDF: {}", kdf_id);
+            return Err(format!("Unsupported keystore KDF {}", kdf_id));
+        }
+        let kdf = KdfParams { m_cost: r.u32()?, t_cost: r.u32()?, p_cost: r.u32()? };
+        if !kdf.within_limits() {
+            error!("Keystore KDF parameters out of range: {:?}", kdf);
+            return Err("Invalid keystore KDF parameters".to_string());
+        }
+        let salt_len = r.u16()? as usize;
+        let salt = r.take(salt_len)?.to_vec();
+        let password = r.slot(WRAPPED_KEY_LEN)?;
+        let duress = if version >= 3 { Some(r.slot(WRAPPED_KEY_LEN)?) } else { None };
+        let recovery = r.slot(WRAPPED_KEY_LEN)?;
+        let (payload, decoy_payload, binding, rename) = if version >= 3 {
+            let payload = r.slot(PAYLOAD_LEN + TAG_LEN)?;
+            let decoy_payload = r.slot(PAYLOAD_LEN + TAG_LEN)?;
+            let binding = r.take(BINDING_LEN)?.to_vec();
+            let rename = r.take(BINDING_LEN)?.to_vec();
+            if !r.rest().is_empty() {
+                error!("Keystore v{} has trailing data", version);
+                return Err("Invalid encrypted data format".to_string());
+            }
+            (payload, Some(decoy_payload), Some(binding), Some(rename))
+        } else {
+            let nonce = r.take(NONCE_LEN)?.to_vec();
+            (Slot { nonce, wrapped: r.rest().to_vec() }, None, None, None)
+        };
+        debug!("Parsed keystore v{} with {:?}", version, kdf);
+        Ok(Keystore {
+            format: Format::Versioned(version),
+            password: PasswordSlot { kdf, salt, slot: password },
+            duress,
+            recovery: Some(recovery),
+            payload,
+            decoy_payload,
+            binding,
+            rename,
+        })
+    }
+
+    /// Unversioned files carry no marker telling the two old layouts apart,
+    /// so the envelope layout is assumed whenever the file is long enough and
+    /// `open` falls back to the single-key layout if the password slot fails.
+    fn parse_legacy(data: &[u8]) -> Result<Self, String> {
+        let mut r = Reader { data };
+        let salt_len = r.u16()? as usize;
+        let salt = r.take(salt_len)?.to_vec();
+        let body = r.rest();
+        if body.len() < NONCE_LEN {
+            error!("Keystore truncated: salt length {}, total {}", salt_len, data.len());
+            return Err("Invalid encrypted data format".to_string());
+        }
+        if body.len() < 2 * (NONCE_LEN + WRAPPED_KEY_LEN) + NONCE_LEN {
+            return Ok(Self::single_key(salt, body));
+        }
+        let mut r = Reader { data: body };
+        let password = r.slot(WRAPPED_KEY_LEN)?;
+        let recovery = r.slot(WRAPPED_KEY_LEN)?;
+        let nonce = r.take(NONCE_LEN)?.to_vec();
+        Ok(Keystore {
+            format: Format::LegacyEnvelope,
+            password: PasswordSlot { kdf: KdfParams::LEGACY, salt, slot: password },
+            duress: None,
+            recovery: Some(recovery),
+            payload: Slot { nonce, wrapped: r.rest().to_vec() },
+            decoy_payload: None,
+            binding: None,
+            rename: None,
+        })
+    }
+
+    fn single_key(salt: Vec<u8>, body: &[u8]) -> Self {
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        let payload = Slot { nonce: nonce.to_vec(), wrapped: ciphertext.to_vec() };
+        Keystore {
+            format: Format::LegacySingleKey,
+            password: PasswordSlot { kdf: KdfParams::LEGACY, salt, slot: payload.clone() },
+            duress: None,
+            recovery: None,
+            payload,
+            decoy_payload: None,
+            binding: None,
+            rename: None,
+        }
+    }
+
+    /// Everything from the magic bytes through the salt.
+    fn header(&self) -> Vec<u8> {
+        let version = match self.format {
+            Format::Versioned(version) => version,
+            _ => FORMAT_VERSION,
+        };
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(version);
+        out.push(KDF_ARGON2ID);
+        out.extend_from_slice(&self.password.kdf.m_cost.to_be_bytes());
+        out.extend_from_slice(&self.password.kdf.t_cost.to_be_bytes());
+        out.extend_from_slice(&self.password.kdf.p_cost.to_be_bytes());
+        out.extend_from_slice(&(self.password.salt.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.password.salt);
+        out
+    }
+
+    /// Associated data for the payload of `role`. Binding the header means
+    /// the version and KDF settings cannot be altered, and binding the
+    /// username means a keystore copied over another account's file no
+    /// longer opens. A v3 primary binds the username with its tags instead.
+    ///
+    /// Only the key wraps are left unbound: they hold nothing but the data
+    /// key, and a wrap moved between files unlocks a data key that fails
+    /// against the other file's payload anyway.
+    fn payload_aad(&self, username: &str, role: Role) -> Vec<u8> {
+        match self.format {
+            Format::Versioned(version) if version >= 3 && role == Role::Primary => self.header(),
+            Format::Versioned(version) if version >= 2 => {
+                let mut aad = self.header();
+                aad.extend_from_slice(&(username.len() as u16).to_be_bytes());
+                aad.extend_from_slice(username.as_bytes());
+                aad
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether the v3 primary, opened with `data_key` and carrying
+    /// `decoy_key`, is bound to `username`: by its own tag, or by a decoy's
+    /// rename tag. Returns whether it took the rename tag.
+    fn check_binding(&self, username: &str, data_key: &[u8], decoy_key: Option<&[u8; KEY_LEN]>) -> Option<bool> {
+        let (Some(binding), Some(rename)) = (&self.binding, &self.rename) else {
+            return Some(false);
+        };
+        let header = self.header();
+        if binding_mac(data_key, BINDING_LABEL, &header, username, &[]).verify_slice(binding).is_ok() {
+            return Some(false);
+        }
+        let decoy_key = decoy_key?;
+        binding_mac(decoy_key, RENAME_LABEL, &header, username, binding).verify_slice(rename).ok()?;
+        Some(true)
+    }
+
+    /// Decrypts the payload of `role` with `key`. Files older than version 3
+    /// have no decoy and store the plaintext without an envelope.
+    fn open_payload(&self, key: &[u8], role: Role, username: &str) -> Option<Envelope> {
+        let (slot, role_byte) = match role {
+            Role::Primary => (&self.payload, ROLE_PRIMARY),
+            Role::Decoy => (self.decoy_payload.as_ref()?, ROLE_DECOY),
+        };
+        let mut padded = decrypt(key, slot, &self.payload_aad(username, role))?;
+        let envelope = match self.format {
+            Format::Versioned(version) if version >= 3 => Envelope::unpad(&padded),
+            _ => Some(Envelope::legacy(padded.clone())),
+        };
+        padded.zeroize();
+        envelope.filter(|envelope| envelope.role == role_byte)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = self.header();
+        let slots = [
+            Some(&self.password.slot),
+            self.duress.as_ref(),
+            self.recovery.as_ref(),
+            Some(&self.payload),
+            self.decoy_payload.as_ref(),
+        ];
+        for slot in slots.into_iter().flatten() {
+            out.extend_from_slice(&slot.nonce);
+            out.extend_from_slice(&slot.wrapped);
+        }
+        for tag in [&self.binding, &self.rename].into_iter().flatten() {
+            out.extend_from_slice(tag);
+        }
+        out
+    }
+}
+
+pub fn derive_password_key(password: &str, salt: &[u8], kdf: KdfParams) -> Result<[u8; KEY_LEN], String> {
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(KEY_LEN)).map_err(|e| {
+        let err = format!("Invalid KDF parameters {:?}: {}", kdf, e);
+        error!("{}", err);
+        err
+    })?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+    let mut key = [0u8; KEY_LEN];
+    argon2.hash_password_into(password.as_bytes(), salt, &mut key).map_err(|e| {
+        let err = format!("Password hashing failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    Ok(key)
+}
+
+fn encrypt(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Slot, String> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, Payload { msg: plaintext, aad }).map_err(|e| {
+        let err = format!("Encryption failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    Ok(Slot { nonce: nonce.to_vec(), wrapped: ciphertext })
+}
+
+fn decrypt(key: &[u8], slot: &Slot, aad: &[u8]) -> Option<Vec<u8>> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    cipher.decrypt(Nonce::from_slice(&slot.nonce), Payload { msg: &slot.wrapped, aad }).ok()
+}
+
+fn unwrap_key(key: &[u8], slot: &Slot) -> Option<[u8; KEY_LEN]> {
+    let mut bytes = decrypt(key, slot, &[])?;
+    let data_key = <[u8; KEY_LEN]>::try_from(bytes.as_slice()).ok();
+    bytes.fill(0);
+    data_key
+}
+
+fn missing(what: &str) -> String {
+    let err = format!("A {} is required to rewrite this keystore", what);
+    error!("{}", err);
+    err
+}
+
+/// Encrypts `plaintext` into a new keystore for `username` that opens with
+/// either `password` or `recovery_key`. The KDF cost is calibrated here and
+/// kept for the life of the file.
+pub fn seal(plaintext: &[u8], username: &str, password: &str, recovery_key: &[u8; KEY_LEN]) -> Result<Vec<u8>, String> {
+    let mut data_key = [0u8; KEY_LEN];
+    OsRng.fill_bytes(&mut data_key);
+    let salt = SaltString::generate(&mut OsRng);
+    let unlocked = Unlocked {
+        plaintext: plaintext.to_vec(),
+        format: Format::Versioned(FORMAT_VERSION),
+        role: Role::Primary,
+        data_key,
+        kdf: KdfParams::calibrated(),
+        salt: salt.as_str().as_bytes().to_vec(),
+        password: None,
+        duress: None,
+        recovery: None,
+        primary_payload: None,
+        primary_binding: None,
+        renamed: false,
+        decoy: None,
+        wipe_primary: false,
+    };
+    data_key.fill(0);
+    reseal(&unlocked, username, Some(password), Some(recovery_key))
+}
+
+/// Rewrites an opened keystore in the current format, bound to `username`.
+///
+/// A password or recovery key passed here gets a freshly wrapped slot for
+/// the account that was opened; when `None`, the existing slot is carried
+/// over unchanged, so callers only need the secrets they are actually
+/// replacing. Passwords reuse the file's salt and KDF cost, which never
+/// change, so a duress slot the caller cannot see keeps working.
+///
+/// The primary rewrites the decoy with it, or fresh random bytes when there
+/// is none. A decoy cannot re-encrypt the primary, so it copies the
+/// primary's slot, payload and binding byte for byte, and tags `username`
+/// so the primary still opens after a rename. Only a decoy set to wipe the
+/// primary replaces it with random bytes.
+pub fn reseal(unlocked: &Unlocked, username: &str, password: Option<&str>, recovery_key: Option<&[u8; KEY_LEN]>) -> Result<Vec<u8>, String> {
+    let mut keystore = Keystore {
+        format: Format::Versioned(FORMAT_VERSION),
+        password: PasswordSlot { kdf: unlocked.kdf, salt: unlocked.salt.clone(), slot: Slot::random(WRAPPED_KEY_LEN) },
+        duress: None,
+        recovery: None,
+        payload: Slot::random(PAYLOAD_LEN + TAG_LEN),
+        decoy_payload: Some(Slot::random(PAYLOAD_LEN + TAG_LEN)),
+        binding: Some(random_bytes(BINDING_LEN)),
+        rename: Some(random_bytes(BINDING_LEN)),
+    };
+    let header = keystore.header();
+    let primary_aad = keystore.payload_aad(username, Role::Primary);
+    let decoy_aad = keystore.payload_aad(username, Role::Decoy);
+    let other = match unlocked.role {
+        Role::Primary => unlocked.duress.as_ref(),
+        Role::Decoy => unlocked.password.as_ref(),
+    };
+    let new_password = password.map(|password| unlocked.wrap_password(password, &unlocked.data_key, other)).transpose()?;
+    let new_recovery = recovery_key.map(|recovery_key| encrypt(recovery_key, &unlocked.data_key, &[])).transpose()?;
+    match unlocked.role {
+        Role::Primary => {
+            keystore.password.slot = new_password.or_else(|| unlocked.password.clone()).ok_or_else(|| missing("password"))?;
+            keystore.duress = Some(unlocked.duress.clone().unwrap_or_else(|| Slot::random(WRAPPED_KEY_LEN)));
+            keystore.recovery = Some(new_recovery.or_else(|| unlocked.recovery.clone()).ok_or_else(|| missing("recovery key"))?);
+            let decoy_key = unlocked.decoy.as_ref().map(|decoy| &decoy.data_key);
+            let flags = if decoy_key.is_some() { FLAG_HAS_DECOY } else { 0 };
+            let envelope = Envelope::pad(ROLE_PRIMARY, flags, decoy_key, &unlocked.plaintext)?;
+            keystore.payload = encrypt(&unlocked.data_key, &envelope, &primary_aad)?;
+            keystore.binding = Some(binding_mac(&unlocked.data_key, BINDING_LABEL, &header, username, &[]).finalize().into_bytes().to_vec());
+            if let Some(decoy) = &unlocked.decoy {
+                let flags = if decoy.wipe_primary { FLAG_WIPE_PRIMARY } else { 0 };
+                let envelope = Envelope::pad(ROLE_DECOY, flags, None, &decoy.plaintext)?;
+                keystore.decoy_payload = Some(encrypt(&decoy.data_key, &envelope, &decoy_aad)?);
+            }
+        }
+        Role::Decoy => {
+            let wipe = unlocked.wipe_primary;
+            if !wipe {
+                keystore.password.slot = unlocked.password.clone().ok_or_else(|| missing("password"))?;
+                keystore.payload = unlocked.primary_payload.clone().ok_or_else(|| missing("primary payload"))?;
+                let binding = unlocked.primary_binding.clone().ok_or_else(|| missing("primary binding"))?;
+                keystore.rename = Some(binding_mac(&unlocked.data_key, RENAME_LABEL, &header, username, &binding).finalize().into_bytes().to_vec());
+                keystore.binding = Some(binding);
+            }
+            keystore.duress = Some(new_password.or_else(|| unlocked.duress.clone()).ok_or_else(|| missing("password"))?);
+            keystore.recovery = Some(match new_recovery {
+                Some(recovery) => recovery,
+                None if wipe => Slot::random(WRAPPED_KEY_LEN),
+                None => unlocked.recovery.clone().ok_or_else(|| missing("recovery key"))?,
+            });
+            // Once the primary is gone there is nothing left to wipe.
+            let envelope = Envelope::pad(ROLE_DECOY, 0, None, &unlocked.plaintext)?;
+            keystore.decoy_payload = Some(encrypt(&unlocked.data_key, &envelope, &decoy_aad)?);
+        }
+    }
+    let out = keystore.serialize();
+    debug!("Keystore sealed as v{}, {} bytes", FORMAT_VERSION, out.len());
+    Ok(out)
+}
+
+/// Decrypts `username`'s keystore with the given secret.
+///
+/// A password is tried against both the primary and the duress slot every
+/// time, so the two take equally long. The recovery key opens whichever
+/// account its slot was last written for.
+///
+/// A secret that unwraps the data key but a payload that then fails to
+/// authenticate means the file was sealed for another account or altered,
+/// which is reported separately from a wrong secret.
+pub fn open(data: &[u8], username: &str, secret: Unlock) -> Result<Unlocked, String> {
+    let mut keystore = Keystore::parse(data)?;
+    debug!("Opening keystore in {:?} format", keystore.format);
+    let (mut data_key, roles, legacy_plaintext): (_, &[Role], _) = match secret {
+        Unlock::Password(password) => {
+            let mut password_key = derive_password_key(password, &keystore.password.salt, keystore.password.kdf)?;
+            let mut data_key = unwrap_key(&password_key, &keystore.password.slot);
+            let duress_key = keystore.duress.as_ref().and_then(|slot| unwrap_key(&password_key, slot));
+            if data_key.is_none() && keystore.format == Format::LegacyEnvelope {
+                debug!("Envelope unlock failed, trying legacy single-key layout");
+                let body = &data[2 + keystore.password.salt.len()..];
+                keystore = Keystore::single_key(keystore.password.salt, body);
+            }
+            let mut plaintext = None;
+            if keystore.format == Format::LegacySingleKey {
+                // The old layout has no data key; mint one for when the file
+                // is rewritten.
+                plaintext = decrypt(&password_key, &keystore.payload, &[]);
+                data_key = plaintext.as_ref().map(|_| {
+                    let mut data_key = [0u8; KEY_LEN];
+                    OsRng.fill_bytes(&mut data_key);
+                    data_key
+                });
+            }
+            password_key.fill(0);
+            match (data_key, duress_key) {
+                (Some(data_key), duress_key) => {
+                    if let Some(mut key) = duress_key {
+                        key.zeroize();
+                    }
+                    (data_key, &[Role::Primary][..], plaintext)
+                }
+                (None, Some(duress_key)) => (duress_key, &[Role::Decoy][..], None),
+                (None, None) => {
+                    let err = "Decryption failed: wrong password or corrupted keystore".to_string();
+                    error!("{}", err);
+                    return Err(err);
+                }
+            }
+        }
+        Unlock::RecoveryKey(recovery_key) => {
+            if recovery_key.len() != KEY_LEN {
+                error!("Invalid recovery key length: {}", recovery_key.len());
+                return Err("Invalid recovery key length".to_string());
+            }
+            let slot = keystore.recovery.as_ref().ok_or_else(|| {
+                let err = "This keystore predates recovery keys; log in with your password to upgrade it".to_string();
+                error!("{}", err);
+                err
+            })?;
+            let data_key = unwrap_key(recovery_key, slot).ok_or_else(|| {
+                // An unversioned file long enough for the envelope layout may
+                // still be a single-key one, which has no recovery slot.
+                let err = if keystore.format == Format::LegacyEnvelope {
+                    "Decryption failed: wrong recovery key, or this keystore predates recovery keys; log in with your password to upgrade it"
+                } else {
+                    "Decryption failed: wrong recovery key or corrupted keystore"
+                }.to_string();
+                error!("{}", err);
+                err
+            })?;
+            (data_key, &[Role::Primary, Role::Decoy][..], None)
+        }
+    };
+    let opened = match legacy_plaintext {
+        Some(plaintext) => Some((Role::Primary, Envelope::legacy(plaintext))),
+        None => roles.iter().find_map(|&role| keystore.open_payload(&data_key, role, username).map(|envelope| (role, envelope))),
+    };
+    let has_decoy = |envelope: &Envelope| envelope.flags & FLAG_HAS_DECOY != 0;
+    let checked = opened.and_then(|(role, envelope)| match role {
+        Role::Primary => {
+            let renamed = keystore.check_binding(username, &data_key, has_decoy(&envelope).then_some(&envelope.decoy_key))?;
+            Some((role, envelope, renamed))
+        }
+        Role::Decoy => Some((role, envelope, false)),
+    });
+    let Some((role, mut envelope, renamed)) = checked else {
+        data_key.fill(0);
+        let err = format!("Keystore does not belong to account {} or has been tampered with", username);
+        error!("{}", err);
+        return Err(err);
+    };
+    if renamed {
+        info!("Keystore was renamed to {} from its duress password", username);
+    }
+    let mut decoy = None;
+    if role == Role::Primary && has_decoy(&envelope) {
+        match keystore.open_payload(&envelope.decoy_key, Role::Decoy, username) {
+            Some(mut opened) => decoy = Some(Decoy {
+                data_key: envelope.decoy_key,
+                plaintext: std::mem::take(&mut opened.plaintext),
+                wipe_primary: opened.flags & FLAG_WIPE_PRIMARY != 0,
+            }),
+            None => error!("Keystore has a damaged secondary payload, dropping it"),
+        }
+    }
+    let password = match keystore.format {
+        Format::LegacySingleKey => None,
+        _ => Some(keystore.password.slot),
+    };
+    Ok(Unlocked {
+        plaintext: std::mem::take(&mut envelope.plaintext),
+        format: keystore.format,
+        role,
+        data_key,
+        kdf: keystore.password.kdf,
+        salt: keystore.password.salt,
+        password,
+        duress: keystore.duress,
+        recovery: keystore.recovery,
+        primary_payload: (role == Role::Decoy).then_some(keystore.payload),
+        primary_binding: keystore.binding.filter(|_| role == Role::Decoy),
+        renamed,
+        decoy,
+        wipe_primary: role == Role::Decoy && envelope.flags & FLAG_WIPE_PRIMARY != 0,
+    })
+}
+
+/// Writes a keystore (or any other account file) through a temporary file
+/// and a rename, so a crash mid-write never leaves a truncated file behind.
+pub fn save(path: &Path, data: &[u8]) -> Result<(), String> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    write(&tmp, data).map_err(|e| {
+        let err = format!("File write failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    rename(&tmp, path).map_err(|e| {
+        let err = format!("File rename failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    info!("Wrote {:?}", path);
+    Ok(())
+}
+
+/// Overwrites a file with random bytes, flushes it to disk and unlinks it.
+pub fn shred(path: &Path) -> Result<(), String> {
+    let len = path.metadata().map(|m| m.len() as usize).map_err(|e| {
+        let err = format!("Failed to stat {:?}: {}", path, e);
+        error!("{}", err);
+        err
+    })?;
+    let mut noise = vec![0u8; len];
+    OsRng.fill_bytes(&mut noise);
+    let mut file = OpenOptions::new().write(true).open(path).map_err(|e| {
+        let err = format!("Failed to open {:?}: {}", path, e);
+        error!("{}", err);
+        err
+    })?;
+    file.write_all(&noise).and_then(|_| file.sync_all()).map_err(|e| {
+        let err = format!("Failed to overwrite {:?}: {}", path, e);
+        error!("{}", err);
+        err
+    })?;
+    drop(file);
+    remove_file(path).map_err(|e| {
+        let err = format!("Failed to remove {:?}: {}", path, e);
+        error!("{}", err);
+        err
+    })?;
+    debug!("Shredded {:?}", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USERNAME: &str = "alice";
+    const PASSWORD: &str = "correct horse battery staple";
+    const DURESS: &str = "seized at the border";
+    const RECOVERY_KEY: [u8; KEY_LEN] = [7u8; KEY_LEN];
+
+    fn open_password(data: &[u8], username: &str, password: &str) -> Result<Unlocked, String> {
+        open(data, username, Unlock::Password(password))
+    }
+
+    fn with_decoy(wipe_primary: bool) -> Vec<u8> {
+        let sealed = seal(b"primary", USERNAME, PASSWORD, &RECOVERY_KEY).unwrap();
+        let mut unlocked = open_password(&sealed, USERNAME, PASSWORD).unwrap();
+        unlocked.set_decoy(DURESS, b"decoy", wipe_primary).unwrap();
+        reseal(&unlocked, USERNAME, None, None).unwrap()
+    }
+
+    fn pack(header: Vec<u8>, slots: Vec<Slot>) -> Vec<u8> {
+        let mut out = header;
+        for slot in slots {
+            out.extend_from_slice(&slot.nonce);
+            out.extend_from_slice(&slot.wrapped);
+        }
+        out
+    }
+
+    /// A keystore in one of the unversioned layouts, which are no longer written.
+    fn legacy(envelope: bool, plaintext: &[u8]) -> Vec<u8> {
+        let salt = b"0123456789abcdef";
+        let data_key = [9u8; KEY_LEN];
+        let password_key = derive_password_key(PASSWORD, salt, KdfParams::LEGACY).unwrap();
+        let slots = if envelope {
+            vec![
+                encrypt(&password_key, &data_key, &[]).unwrap(),
+                encrypt(&RECOVERY_KEY, &data_key, &[]).unwrap(),
+                encrypt(&data_key, plaintext, &[]).unwrap(),
+            ]
+        } else {
+            vec![encrypt(&password_key, plaintext, &[]).unwrap()]
+        };
+        let mut header = (salt.len() as u16).to_be_bytes().to_vec();
+        header.extend_from_slice(salt);
+        pack(header, slots)
+    }
+
+    /// A keystore in the `version` 1 or 2 layout, which is no longer written.
+    fn versioned(version: u8, plaintext: &[u8]) -> Vec<u8> {
+        let kdf = KdfParams::calibrated();
+        let salt = b"0123456789abcdef".to_vec();
+        let data_key = [9u8; KEY_LEN];
+        let keystore = Keystore {
+            format: Format::Versioned(version),
+            password: PasswordSlot { kdf, salt: salt.clone(), slot: Slot::random(WRAPPED_KEY_LEN) },
+            duress: None,
+            recovery: None,
+            payload: Slot::random(0),
+            decoy_payload: None,
+            binding: None,
+            rename: None,
+        };
+        let password_key = derive_password_key(PASSWORD, &salt, kdf).unwrap();
+        pack(keystore.header(), vec![
+            encrypt(&password_key, &data_key, &[]).unwrap(),
+            encrypt(&RECOVERY_KEY, &data_key, &[]).unwrap(),
+            encrypt(&data_key, plaintext, &keystore.payload_aad(USERNAME, Role::Primary)).unwrap(),
+        ])
+    }
+
+    #[test]
+    fn seal_and_open_roundtrip() {
+        let sealed = seal(b"secret", USERNAME, PASSWORD, &RECOVERY_KEY).unwrap();
+        let unlocked = open_password(&sealed, USERNAME, PASSWORD).unwrap();
+        assert_eq!(unlocked.plaintext, b"secret");
+        assert_eq!(unlocked.role(), Role::Primary);
+        assert!(!unlocked.outdated());
+        assert!(unlocked.has_recovery());
+        let recovered = open(&sealed, USERNAME, Unlock::RecoveryKey(&RECOVERY_KEY)).unwrap();
+        assert_eq!(recovered.plaintext, b"secret");
+        assert!(open_password(&sealed, USERNAME, "wrong password").is_err());
+        assert!(open(&sealed, USERNAME, Unlock::RecoveryKey(&[8u8; KEY_LEN])).is_err());
+        assert!(open_password(&sealed, "mallory", PASSWORD).is_err());
+    }
+
+    #[test]
+    fn legacy_files_upgrade_to_current() {
+        for envelope in [false, true] {
+            let old = legacy(envelope, b"secret");
+            let unlocked = open_password(&old, USERNAME, PASSWORD).unwrap();
+            assert_eq!(unlocked.plaintext, b"secret");
+            assert!(unlocked.outdated());
+            assert_eq!(unlocked.has_recovery(), envelope);
+            // The single-key layout has no recovery slot to carry over.
+            let recovery_key = if envelope { None } else { Some(&RECOVERY_KEY) };
+            let upgraded = reseal(&unlocked, USERNAME, Some(PASSWORD), recovery_key).unwrap();
+            assert_eq!(upgraded[MAGIC.len()], FORMAT_VERSION);
+            let reopened = open_password(&upgraded, USERNAME, PASSWORD).unwrap();
+            assert_eq!(reopened.plaintext, b"secret");
+            assert!(!reopened.outdated());
+            let recovered = open(&upgraded, USERNAME, Unlock::RecoveryKey(&RECOVERY_KEY)).unwrap();
+            assert_eq!(recovered.plaintext, b"secret");
+        }
+    }
+
+    #[test]
+    fn versioned_files_upgrade_to_current() {
+        for version in [1, 2] {
+            let old = versioned(version, b"secret");
+            let unlocked = open_password(&old, USERNAME, PASSWORD).unwrap();
+            assert_eq!(unlocked.plaintext, b"secret");
+            assert!(unlocked.outdated(), "v{} should be rewritten", version);
+            assert_eq!(unlocked.username_bound(), version >= 2);
+            let upgraded = reseal(&unlocked, USERNAME, Some(PASSWORD), None).unwrap();
+            assert_eq!(upgraded[MAGIC.len()], FORMAT_VERSION);
+            let reopened = open_password(&upgraded, USERNAME, PASSWORD).unwrap();
+            assert_eq!(reopened.plaintext, b"secret");
+            assert!(!reopened.outdated());
+            // The recovery slot is carried over as it was.
+            let recovered = open(&upgraded, USERNAME, Unlock::RecoveryKey(&RECOVERY_KEY)).unwrap();
+            assert_eq!(recovered.plaintext, b"secret");
+            assert!(open_password(&upgraded, "mallory", PASSWORD).is_err());
+        }
+        // Version 2 already bound the username.
+        assert!(open_password(&versioned(2, b"secret"), "mallory", PASSWORD).is_err());
+        assert!(open_password(&versioned(1, b"secret"), "mallory", PASSWORD).is_ok());
+    }
+
+    #[test]
+    fn doctored_kdf_parameters_are_refused() {
+        let sealed = seal(b"secret", USERNAME, PASSWORD, &RECOVERY_KEY).unwrap();
+        // m_cost follows MAGIC, the version and the KDF id.
+        let at = MAGIC.len() + 2;
+        let mut doctored = sealed.clone();
+        doctored[at..at + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = open_password(&doctored, USERNAME, PASSWORD).err().unwrap();
+        assert!(err.contains("KDF parameters"), "{}", err);
+    }
+
+    #[test]
+    fn recovery_key_on_single_key_file_asks_for_password() {
+        let salt = b"0123456789abcdef";
+        let mut legacy = (salt.len() as u16).to_be_bytes().to_vec();
+        legacy.extend_from_slice(salt);
+        legacy.extend_from_slice(&[1u8; NONCE_LEN + 40]);
+        let err = open(&legacy, USERNAME, Unlock::RecoveryKey(&RECOVERY_KEY)).err().unwrap();
+        assert!(err.contains("predates recovery keys"), "{}", err);
+    }
+
+    #[test]
+    fn duress_password_opens_decoy() {
+        let sealed = with_decoy(false);
+        let primary = open_password(&sealed, USERNAME, PASSWORD).unwrap();
+        assert_eq!(primary.role(), Role::Primary);
+        assert_eq!(primary.plaintext, b"primary");
+        assert!(primary.has_decoy());
+        let decoy = open_password(&sealed, USERNAME, DURESS).unwrap();
+        assert_eq!(decoy.role(), Role::Decoy);
+        assert_eq!(decoy.plaintext, b"decoy");
+        assert!(!decoy.wipe_pending());
+        assert_eq!(sealed.len(), seal(b"primary", USERNAME, PASSWORD, &RECOVERY_KEY).unwrap().len());
+    }
+
+    #[test]
+    fn decoy_reseal_keeps_primary() {
+        let sealed = with_decoy(false);
+        let decoy = open_password(&sealed, USERNAME, DURESS).unwrap();
+        let resealed = reseal(&decoy, USERNAME, None, None).unwrap();
+        assert_eq!(open_password(&resealed, USERNAME, PASSWORD).unwrap().plaintext, b"primary");
+        assert_eq!(open_password(&resealed, USERNAME, DURESS).unwrap().plaintext, b"decoy");
+    }
+
+    #[test]
+    fn decoy_rename_keeps_primary() {
+        let sealed = with_decoy(false);
+        let decoy = open_password(&sealed, USERNAME, DURESS).unwrap();
+        let renamed = reseal(&decoy, "bob", None, None).unwrap();
+        let primary = open_password(&renamed, "bob", PASSWORD).unwrap();
+        assert_eq!(primary.role(), Role::Primary);
+        assert_eq!(primary.plaintext, b"primary");
+        assert!(primary.has_decoy());
+        assert!(primary.outdated());
+        // Once the primary rewrites the file it is bound to the new name.
+        let rebound = reseal(&primary, "bob", None, None).unwrap();
+        let primary = open_password(&rebound, "bob", PASSWORD).unwrap();
+        assert!(!primary.outdated());
+        assert!(open_password(&rebound, USERNAME, PASSWORD).is_err());
+        assert_eq!(open_password(&rebound, "bob", DURESS).unwrap().plaintext, b"decoy");
+    }
+
+    #[test]
+    fn decoy_wipe_destroys_primary() {
+        let sealed = with_decoy(true);
+        let decoy = open_password(&sealed, USERNAME, DURESS).unwrap();
+        assert!(decoy.wipe_pending());
+        let wiped = reseal(&decoy, USERNAME, None, None).unwrap();
+        assert!(open_password(&wiped, USERNAME, PASSWORD).is_err());
+        assert!(open(&wiped, USERNAME, Unlock::RecoveryKey(&RECOVERY_KEY)).is_err());
+        let decoy = open_password(&wiped, USERNAME, DURESS).unwrap();
+        assert_eq!(decoy.plaintext, b"decoy");
+        assert!(!decoy.wipe_pending());
+    }
+}
@@ -3,33 +3,147 @@
     windows_subsystem = "windows"
 )]
 
-use aes_gcm::{aead::{Aead, AeadCore, KeyInit, OsRng}, Aes256Gcm, Nonce, Key};
-use argon2::{Argon2, Algorithm, Version, Params};
-use argon2::password_hash::SaltString;
+use aes_gcm::aead::OsRng;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tauri::{Manager, Emitter};
-use tauri_plugin_fs;
-use ed25519_dalek::SigningKey;
 use x25519_dalek::{StaticSecret, PublicKey};
-use rand::{RngCore, rngs::OsRng as RandOsRng};
+use rand::RngCore;
 use base64::{Engine as _, engine::general_purpose};
-use std::fs::{create_dir_all, write, read};
-use std::sync::Mutex;
-use std::path::PathBuf;
-use nostr_sdk::{Client, Options, Keys, Kind, Filter, Tag, TagKind, ToBech32, FromBech32, EventBuilder, RelayPoolNotification};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read, read_dir, remove_dir_all, rename, write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::path::{Component, Path, PathBuf};
+use nostr_sdk::{Client, Options, Keys, Kind, Filter, Tag, TagKind, ToBech32, FromBech32, Event, EventBuilder, RelayPoolNotification, UnsignedEvent};
+use nostr_sdk::nips::nip59::UnwrappedGift;
 use std::borrow::Cow;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::spawn;
-use hex;
-use tracing::{info, error, debug};
+use tokio::task::JoinHandle;
+use tracing::{info, error, debug, warn};
 
-#[derive(Serialize, Deserialize, Clone)]
+mod accounts;
+mod attempts;
+mod backup;
+mod identity;
+mod keystore;
+mod message;
+mod password;
+mod prekeys;
+mod ratchet;
+mod secret;
+mod settings;
+mod shares;
+mod storage;
+mod trust;
+
+use attempts::LoginAttempts;
+use identity::Identity;
+use keystore::{Unlock, Unlocked};
+use ed25519_dalek::VerifyingKey;
+use message::{Content, EncryptedPayload, Format};
+use prekeys::PeerBundle;
+use ratchet::RatchetStore;
+use trust::{Trust, TrustStore};
+use secret::SecretString;
+use settings::Settings;
+use zeroize::Zeroizing;
+
+/// The account record kept inside the keystore. It is only read at unlock
+/// and turned into an `Identity` straight away; commands never see it.
+#[derive(Deserialize)]
 struct LoginData {
     username: String,
-    ed25519_private: String,
-    x25519_private: String,
-    nostr_private: String,
+    ed25519_private: SecretString,
+    x25519_private: SecretString,
+    nostr_private: SecretString,
+}
+
+/// Borrowed view of `LoginData` used to write it back into a keystore, the
+/// only place its secrets are ever serialized.
+#[derive(Serialize)]
+struct StoredLoginData<'a> {
+    username: &'a str,
+    ed25519_private: &'a str,
+    x25519_private: &'a str,
+    nostr_private: &'a str,
+}
+
+/// Error returned by session commands after an idle auto-lock, until the
+/// password is entered again. The frontend matches on it.
+const LOCKED_ERROR: &str = "Locked";
+/// Error returned by `send_nostr_message` while a verified contact's key
+/// change waits for the user. The frontend matches on it.
+const KEY_CHANGED_ERROR: &str = "KeyChanged";
+/// How often the watchdog looks for idle sessions.
+const AUTO_LOCK_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+/// How often prekeys are checked for rotation and top-up in the background.
+const PREKEY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long to wait for relays when fetching a contact's prekey bundle.
+const BUNDLE_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Minimum time between fetches of one sender's announced key while their
+/// messages carry a key that does not match it.
+const ANNOUNCEMENT_REFRESH: Duration = Duration::from_secs(60);
+/// Minimum time between fetches for a sender who announced no key, or whose
+/// announcement could not be fetched. Each fetch holds up the receive loop
+/// for up to `BUNDLE_FETCH_TIMEOUT`.
+const ANNOUNCEMENT_RETRY: Duration = Duration::from_secs(10 * 60);
+/// Tries at rewriting the keystore without the primary after a duress
+/// unlock, before the whole file is shredded instead.
+const WIPE_ATTEMPTS: usize = 3;
+
+/// The logged-in account. Keys are decoded once at login and shared between
+/// commands, so cloning a session never copies key material.
+#[derive(Clone)]
+struct Session {
+    username: String,
+    identity: Arc<Identity>,
+    /// Double ratchet sessions and prekeys of this identity, shared with the
+    /// receive and prekey tasks.
+    ratchets: Arc<Mutex<RatchetStore>>,
+    /// Keys pinned per contact.
+    contacts: Arc<Mutex<TrustStore>>,
+    /// Idle time after which the session locks itself, if enabled.
+    auto_lock: Option<Duration>,
+    last_active: Instant,
+}
+
+impl Session {
+    fn new(username: &str, identity: Identity, data_dir: &Path, settings: &Settings) -> Self {
+        let ratchets = RatchetStore::open(data_dir, &identity.x25519);
+        let contacts = TrustStore::open(data_dir, &identity.x25519);
+        Session {
+            username: username.to_string(),
+            identity: Arc::new(identity),
+            ratchets: Arc::new(Mutex::new(ratchets)),
+            contacts: Arc::new(Mutex::new(contacts)),
+            auto_lock: settings.auto_lock(),
+            last_active: Instant::now(),
+        }
+    }
+
+    fn idle_expired(&self) -> bool {
+        self.auto_lock.is_some_and(|limit| self.last_active.elapsed() >= limit)
+    }
+
+    /// Locks the ratchet store. A lock poisoned by a panic elsewhere is
+    /// reported like any other failure instead of panicking again.
+    fn lock_ratchets(&self) -> Result<MutexGuard<'_, RatchetStore>, String> {
+        self.ratchets.lock().map_err(|e| {
+            let err = format!("Failed to lock ratchet store: {}", e);
+            error!("{}", err);
+            err
+        })
+    }
+
+    /// Locks the contact store, see `lock_ratchets`.
+    fn lock_contacts(&self) -> Result<MutexGuard<'_, TrustStore>, String> {
+        self.contacts.lock().map_err(|e| {
+            let err = format!("Failed to lock contact store: {}", e);
+            error!("{}", err);
+            err
+        })
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -39,20 +153,20 @@ struct Response {
     data: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct EncryptedPayload {
-    ciphertext: String,
-    nonce: String,
-}
-
 #[derive(Default)]
 struct AppState {
-    login: Mutex<Option<LoginData>>,
+    login: Mutex<Option<Session>>,
     nostr_client: Mutex<Option<Client>>,
+    /// Listener spawned by `receive_nostr_messages`.
+    receive_task: Mutex<Option<JoinHandle<()>>>,
+    /// Prekey rotation and publishing spawned by `init_nostr_client`.
+    prekey_task: Mutex<Option<JoinHandle<()>>>,
+    /// Account whose session was auto-locked, so commands can answer
+    /// `LOCKED_ERROR` rather than "Not logged in" until it is unlocked.
+    locked: Mutex<Option<String>>,
 }
 
-fn get_account_path(app_handle: &tauri::AppHandle, username: &str) -> Result<PathBuf, String> {
-    info!("Getting account path for username: {}", username);
+fn get_accounts_dir(app_handle: &tauri::AppHandle) -> Result<PathBuf, String> {
     let app_data = app_handle.path().app_data_dir().map_err(|e| {
         let err = format!("Failed to get app data dir: {}", e);
         error!("{}", err);
@@ -64,83 +178,569 @@ fn get_account_path(app_handle: &tauri::AppHandle, username: &str) -> Result<Pat
         error!("{}", err);
         err
     })?;
-    debug!("Account path: {:?}", accounts_dir.join(format!("{}.enc", username)));
-    Ok(accounts_dir.join(format!("{}.enc", username)))
+    Ok(accounts_dir)
 }
 
-#[tauri::command]
-async fn create_account(
-    state: tauri::State<'_, AppState>,
-    username: String,
-    password: String,
-    app_handle: tauri::AppHandle
-) -> Result<Response, String> {
-    info!("Creating account for username: {}", username);
-    if username.is_empty() || password.is_empty() {
-        error!("Create account failed: Username or password empty");
-        return Err("Username and password cannot be empty".to_string());
-    }
-    let path = get_account_path(&app_handle, &username)?;
-    if path.exists() {
-        error!("Create account failed: Username already exists at {:?}", path);
-        return Err("Username already exists on this device.".to_string());
-    }
-    let mut recovery_key_bytes = [0u8; 32];
-    OsRng.fill_bytes(&mut recovery_key_bytes);
-    let recovery_key = general_purpose::STANDARD.encode(&recovery_key_bytes);
-    let mut csprng = RandOsRng;
-    let ed25519_keypair = SigningKey::generate(&mut csprng);
-    let x25519_secret = StaticSecret::random_from_rng(&mut csprng);
-    let nostr_keys = Keys::generate();
-    let login_data = LoginData {
-        username: username.clone(),
-        ed25519_private: hex::encode(ed25519_keypair.to_bytes()),
-        x25519_private: hex::encode(x25519_secret.to_bytes()),
-        nostr_private: nostr_keys.secret_key().to_bech32().map_err(|e| {
-            let err = format!("Bech32 error: {}", e);
+/// Returns the current session and marks it active. A session idle past its
+/// limit is locked here instead of being handed out, so it cannot be used in
+/// the gap before the watchdog's next check.
+async fn current_session(state: &AppState) -> Result<Session, String> {
+    let expired = {
+        let mut guard = state.login.lock().map_err(|e| {
+            let err = format!("Failed to lock login state: {}", e);
             error!("{}", err);
             err
-        })?,
+        })?;
+        match guard.as_mut() {
+            Some(session) if !session.idle_expired() => {
+                session.last_active = Instant::now();
+                return Ok(session.clone());
+            }
+            Some(_) => true,
+            None => false,
+        }
     };
-    let plaintext = serde_json::to_string(&login_data).map_err(|e| {
-        let err = e.to_string();
-        error!("Serialization failed: {}", err);
+    if expired {
+        info!("Session idle past its limit, locking");
+        end_session(state, true).await;
+    }
+    if state.locked.lock().unwrap().is_some() {
+        error!("Session is locked");
+        return Err(LOCKED_ERROR.to_string());
+    }
+    let err = "Not logged in".to_string();
+    error!("{}", err);
+    Err(err)
+}
+
+/// Replaces whatever session was active with `session`.
+async fn start_session(state: &AppState, session: Session) {
+    end_session(state, false).await;
+    info!("Session started for {}", session.username);
+    state.login.lock().unwrap().replace(session);
+}
+
+/// Clears the session along with everything that uses its keys: the receive
+/// task is stopped and the Nostr client shut down. With `lock`, the account
+/// is remembered so commands report it as locked rather than logged out.
+async fn end_session(state: &AppState, lock: bool) {
+    let session = state.login.lock().unwrap().take();
+    if let Some(task) = state.receive_task.lock().unwrap().take() {
+        task.abort();
+        debug!("Receive task stopped");
+    }
+    if let Some(task) = state.prekey_task.lock().unwrap().take() {
+        task.abort();
+        debug!("Prekey task stopped");
+    }
+    let client = state.nostr_client.lock().unwrap().take();
+    if let Some(client) = client {
+        if let Err(e) = client.shutdown().await {
+            error!("Nostr client shutdown failed: {}", e);
+        }
+        debug!("Nostr client shut down");
+    }
+    let mut locked = state.locked.lock().unwrap();
+    match (lock, session) {
+        (true, Some(session)) => {
+            info!("Session locked for {}", session.username);
+            locked.replace(session.username.clone());
+        }
+        (true, None) => {}
+        (false, session) => {
+            if let Some(session) = session {
+                info!("Session ended for {}", session.username);
+            }
+            locked.take();
+        }
+    }
+}
+
+/// Locks the session once it has been idle past its auto-lock limit and
+/// tells the frontend, which returns to the login screen.
+async fn auto_lock_watchdog(app_handle: tauri::AppHandle) {
+    let mut interval = tokio::time::interval(AUTO_LOCK_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let state = app_handle.state::<AppState>();
+        let expired = state.login.lock().map(|guard| guard.as_ref().is_some_and(Session::idle_expired)).unwrap_or(false);
+        if expired {
+            info!("Auto-locking idle session");
+            end_session(&state, true).await;
+            if let Err(e) = app_handle.emit("session_locked", ()) {
+                error!("Failed to emit session_locked: {}", e);
+            }
+        }
+    }
+}
+
+/// Resolves an existing account's keystore through the account index.
+/// Returns the username in its canonical form along with the path.
+fn get_account_path(app_handle: &tauri::AppHandle, username: &str) -> Result<(String, PathBuf), String> {
+    info!("Getting account path for username: {}", username);
+    let accounts_dir = get_accounts_dir(app_handle)?;
+    let account = accounts::find(&accounts_dir, username)?;
+    debug!("Account path: {:?}", account.keystore_path(&accounts_dir));
+    Ok((account.username.clone(), account.keystore_path(&accounts_dir)))
+}
+
+/// Reserves a keystore path for a new account. Returns the username in its
+/// canonical form along with the path.
+fn claim_account_path(app_handle: &tauri::AppHandle, username: &str) -> Result<(String, PathBuf), String> {
+    info!("Claiming account path for username: {}", username);
+    let accounts_dir = get_accounts_dir(app_handle)?;
+    let account = accounts::claim(&accounts_dir, username)?;
+    debug!("Account path: {:?}", account.keystore_path(&accounts_dir));
+    Ok((account.username.clone(), account.keystore_path(&accounts_dir)))
+}
+
+/// Directory for everything an account stores besides its keystore:
+/// contacts, settings and message history. It is created by whoever writes
+/// the first file and travels with the keystore in account bundles.
+fn get_account_data_dir(app_handle: &tauri::AppHandle, username: &str) -> Result<PathBuf, String> {
+    let accounts_dir = get_accounts_dir(app_handle)?;
+    Ok(accounts::find(&accounts_dir, username)?.data_dir(&accounts_dir))
+}
+
+fn decode_recovery_key(recovery_key: &str) -> Result<Vec<u8>, String> {
+    general_purpose::STANDARD.decode(recovery_key.trim()).map_err(|e| {
+        let err = format!("Invalid recovery key: {}", e);
+        error!("{}", err);
+        err
+    })
+}
+
+/// Reads `username`'s account file and unlocks it with the recovery key if
+/// one was given, otherwise with the password.
+///
+/// Every attempt goes through the account's throttle: repeated failures
+/// impose growing delays, enough of them make the app refuse the password
+/// alone, and the outcome is added to the audit trail under `action`. The
+/// throttle lives in unauthenticated files and only holds back guessing
+/// through the app; see `attempts::LoginAttempts`. The key derivation runs
+/// on a blocking thread, outside the throttle's lock.
+///
+/// A duress unlock that asked for the primary to be destroyed wipes it here,
+/// before the caller sees the file, so no command can keep, export or act on
+/// the real account through the duress password.
+async fn open_keystore(path: &Path, username: &str, password: &str, recovery_key: Option<&str>, action: &str) -> Result<Unlocked, String> {
+    debug!("Checking if account exists at: {:?}", path);
+    if !path.exists() {
+        error!("Account does not exist at {:?}", path);
+        return Err("Account does not exist".to_string());
+    }
+    let encrypted_data = read(path).map_err(|e| {
+        let err = format!("File read failed: {}", e);
+        error!("{}", err);
         err
     })?;
-    let salt = SaltString::generate(&mut OsRng);
-    let salt_bytes = salt.as_ref().as_bytes();
-    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(65536, 2, 1, Some(32)).unwrap());
-    let mut key = [0u8; 32];
-    argon2.hash_password_into(password.as_bytes(), salt_bytes, &mut key).map_err(|e| {
-        let err = e.to_string();
-        error!("Password hashing failed: {}", err);
+    debug!("Encrypted data length: {}", encrypted_data.len());
+    let data_dir = path.with_extension("");
+    let method = if recovery_key.is_some() { "recovery_key" } else { "password" };
+    let recovery_key_bytes = recovery_key.map(decode_recovery_key).transpose()?.map(Zeroizing::new);
+    {
+        let _guard = attempts::lock();
+        let mut attempts = LoginAttempts::load(&data_dir)?;
+        let settings = Settings::load(&data_dir).unwrap_or_default();
+        let wait = attempts.wait_secs(attempts::now());
+        let refused = if wait > 0 {
+            Some(format!("Too many failed attempts. Try again in {} seconds", wait))
+        } else if recovery_key.is_none() && attempts.recovery_key_required(settings.recovery_key_after_failures) {
+            Some("Too many failed attempts. Log in with your recovery key".to_string())
+        } else {
+            None
+        };
+        if let Some(err) = refused {
+            error!("Unlock refused for {}: {}", username, err);
+            attempts.record(action, method, Err(&err));
+            attempts.save(&data_dir)?;
+            return Err(err);
+        }
+        attempts.begin();
+        attempts.save(&data_dir)?;
+    }
+    let owned_username = username.to_string();
+    let secret = Zeroizing::new(password.to_string());
+    let unlocked = blocking(move || Ok(match &recovery_key_bytes {
+        Some(recovery_key_bytes) => {
+            debug!("Recovery key bytes length: {}", recovery_key_bytes.len());
+            keystore::open(&encrypted_data, &owned_username, Unlock::RecoveryKey(recovery_key_bytes))
+        }
+        None => keystore::open(&encrypted_data, &owned_username, Unlock::Password(&secret)),
+    }))
+    .await?;
+    {
+        let _guard = attempts::lock();
+        let mut attempts = LoginAttempts::load(&data_dir)?;
+        attempts.record(action, method, unlocked.as_ref().map(|_| ()).map_err(String::as_str));
+        attempts.save(&data_dir)?;
+    }
+    let mut unlocked = unlocked?;
+    if unlocked.wipe_pending() {
+        let mut login_data = LoginData::from_keystore(&unlocked, username)?;
+        let identity = login_data.identity()?;
+        // Older files may spell the username differently from the index.
+        login_data.username = username.to_string();
+        login_data.write_into(&mut unlocked)?;
+        let (path, owned_username) = (path.to_path_buf(), username.to_string());
+        let password = recovery_key.is_none().then(|| Zeroizing::new(password.to_string()));
+        unlocked = blocking(move || {
+            wipe_primary(&path, &data_dir, &owned_username, &unlocked, password.as_deref().map(String::as_str), &identity)?;
+            Ok(unlocked)
+        })
+        .await?;
+    }
+    Ok(unlocked)
+}
+
+/// Runs `task` on a blocking thread. Anything that derives a key with Argon2
+/// goes through here, as it takes about a second and most of a gigabyte of
+/// memory and would otherwise stall the async runtime.
+async fn blocking<T: Send + 'static>(task: impl FnOnce() -> Result<T, String> + Send + 'static) -> Result<T, String> {
+    tauri::async_runtime::spawn_blocking(task).await.map_err(|e| {
+        let err = format!("Background task failed: {}", e);
+        error!("{}", err);
+        err
+    })?
+}
+
+/// Rewrites an opened keystore in the current format for `username`, keeping
+/// its existing slots. Files that predate the recovery slot get a new
+/// recovery key, which is returned for display.
+fn rewrite_keystore(path: &Path, username: &str, unlocked: &Unlocked, password: Option<&str>) -> Result<Option<String>, String> {
+    let mut recovery_key_bytes = None;
+    if !unlocked.has_recovery() {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        recovery_key_bytes = Some(bytes);
+    }
+    let encrypted_data = keystore::reseal(unlocked, username, password, recovery_key_bytes.as_ref())?;
+    keystore::save(path, &encrypted_data)?;
+    let recovery_key = recovery_key_bytes.as_mut().map(|bytes| {
+        let encoded = general_purpose::STANDARD.encode(&bytes);
+        bytes.fill(0);
+        encoded
+    });
+    info!("Keystore rewritten as v{}", keystore::FORMAT_VERSION);
+    Ok(recovery_key)
+}
+
+/// Destroys the real account behind a duress unlock that asked for it: its
+/// session, prekey and contact stores are shredded, then the keystore is
+/// rewritten without it. If the keystore cannot be rewritten, it is shredded
+/// whole, decoy included, rather than left recoverable.
+///
+/// Only current files have a decoy, and they always carry a recovery slot,
+/// so the rewrite never issues a new recovery key.
+fn wipe_primary(path: &Path, data_dir: &Path, username: &str, unlocked: &Unlocked, password: Option<&str>, identity: &Identity) -> Result<(), String> {
+    info!("Wiping the primary account of {}", username);
+    let shredded = ratchet::shred_other_stores(data_dir, &identity.x25519)
+        .and_then(|_| trust::shred_other_stores(data_dir, &identity.x25519));
+    let mut last_error = String::new();
+    for attempt in 1..=WIPE_ATTEMPTS {
+        match rewrite_keystore(path, username, unlocked, password) {
+            Ok(_) => {
+                // Checked only now so a failed shred never stops the keystore
+                // rewrite that matters most.
+                return shredded;
+            }
+            Err(e) => {
+                error!("Wipe attempt {} of {} failed: {}", attempt, WIPE_ATTEMPTS, e);
+                last_error = e;
+            }
+        }
+    }
+    keystore::shred(path)?;
+    let err = format!("Failed to open account: {}", last_error);
+    error!("{}", err);
+    Err(err)
+}
+
+/// Collects every file under `dir` into `files`, keyed by `prefix` plus the
+/// `/`-separated relative path.
+fn collect_files(dir: &Path, prefix: &str, files: &mut BTreeMap<String, Vec<u8>>) -> Result<(), String> {
+    let entries = read_dir(dir).map_err(|e| {
+        let err = format!("Failed to read {:?}: {}", dir, e);
+        error!("{}", err);
         err
     })?;
-    let cipher = Aes256Gcm::new(&Key::<Aes256Gcm>::from_slice(&key));
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    let ciphertext = cipher.encrypt(&nonce, plaintext.as_bytes())
-        .map_err(|e| {
-            let err = format!("Encryption failed: {}", e);
+    for entry in entries {
+        let entry = entry.map_err(|e| {
+            let err = format!("Failed to read {:?}: {}", dir, e);
             error!("{}", err);
             err
         })?;
-    let mut encrypted_data = Vec::new();
-    encrypted_data.extend_from_slice(&(salt_bytes.len() as u16).to_be_bytes());
-    encrypted_data.extend_from_slice(salt_bytes);
-    encrypted_data.extend_from_slice(nonce.as_slice());
-    encrypted_data.extend_from_slice(&ciphertext);
-    write(&path, encrypted_data).map_err(|e| {
-        let err = format!("File write failed: {}", e);
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(&path, &format!("{}/", name), files)?;
+        } else {
+            let data = read(&path).map_err(|e| {
+                let err = format!("File read failed: {}", e);
+                error!("{}", err);
+                err
+            })?;
+            files.insert(name, data);
+        }
+    }
+    Ok(())
+}
+
+/// Shreds every file under `dir`, then removes the directory tree.
+fn shred_dir(dir: &Path) -> Result<(), String> {
+    let mut files = BTreeMap::new();
+    collect_files(dir, "", &mut files)?;
+    for name in files.keys() {
+        keystore::shred(&dir.join(name))?;
+    }
+    remove_dir_all(dir).map_err(|e| {
+        let err = format!("Failed to remove {:?}: {}", dir, e);
+        error!("{}", err);
+        err
+    })
+}
+
+/// Maps a bundle entry under `data/` to a path inside `data_dir`, refusing
+/// anything that could land outside it.
+fn bundle_entry_path(data_dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(name.strip_prefix("data/").unwrap_or_default());
+    let safe = !relative.as_os_str().is_empty()
+        && relative.components().all(|c| matches!(c, Component::Normal(_)));
+    if !safe {
+        error!("Refusing unsafe bundle entry: {}", name);
+        return Err(format!("Invalid account bundle: unsafe entry {}", name));
+    }
+    Ok(data_dir.join(relative))
+}
+
+/// Writes an imported account into `staging`: its keystore first, rebound
+/// to `username`, then the bundle's data files under `data/`.
+fn stage_import(staging: &Path, username: &str, unlocked: &Unlocked, password: Option<&str>, files: &BTreeMap<String, Vec<u8>>) -> Result<Option<String>, String> {
+    let data_dir = staging.join("data");
+    let mut targets = Vec::new();
+    for (name, contents) in files {
+        targets.push((bundle_entry_path(&data_dir, name)?, contents));
+    }
+    create_dir_all(staging).map_err(|e| {
+        let err = format!("Failed to create {:?}: {}", staging, e);
         error!("{}", err);
         err
     })?;
-    state.login.lock().unwrap().replace(login_data.clone());
-    key.fill(0);
+    let new_recovery_key = rewrite_keystore(&staging.join(backup::KEYSTORE_ENTRY), username, unlocked, password)?;
+    for (target, contents) in targets {
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent).map_err(|e| {
+                let err = format!("Failed to create {:?}: {}", parent, e);
+                error!("{}", err);
+                err
+            })?;
+        }
+        write(&target, contents).map_err(|e| {
+            let err = format!("File write failed: {}", e);
+            error!("{}", err);
+            err
+        })?;
+    }
+    Ok(new_recovery_key)
+}
+
+/// Moves a staged import to the keystore `path` it was claimed under. The
+/// keystore moves last, since an account only counts as existing once its
+/// keystore is in place; if that fails, the moved data is removed again.
+fn install_import(staging: &Path, path: &Path) -> Result<(), String> {
+    let staged_data = staging.join("data");
+    let data_dir = path.with_extension("");
+    if staged_data.is_dir() {
+        rename(&staged_data, &data_dir).map_err(|e| {
+            let err = format!("Failed to move imported data: {}", e);
+            error!("{}", err);
+            err
+        })?;
+    }
+    rename(staging.join(backup::KEYSTORE_ENTRY), path).map_err(|e| {
+        let err = format!("Failed to move imported keystore: {}", e);
+        error!("{}", err);
+        if data_dir.exists() {
+            if let Err(e) = shred_dir(&data_dir) {
+                error!("Failed to remove imported data {:?}: {}", data_dir, e);
+            }
+        }
+        err
+    })
+}
+
+impl LoginData {
+    fn from_identity(username: &str, identity: &Identity) -> Result<Self, String> {
+        Ok(LoginData {
+            username: username.to_string(),
+            ed25519_private: SecretString::new(hex::encode(Zeroizing::new(identity.ed25519.to_bytes()))),
+            x25519_private: SecretString::new(hex::encode(Zeroizing::new(identity.x25519.to_bytes()))),
+            nostr_private: SecretString::new(identity.nostr.secret_key().to_bech32().map_err(|e| {
+                let err = format!("Bech32 error: {}", e);
+                error!("{}", err);
+                err
+            })?),
+        })
+    }
+
+    /// Decodes the stored keys.
+    fn identity(&self) -> Result<Identity, String> {
+        let ed25519 = ed25519_dalek::SigningKey::from_bytes(&*self.ed25519_private.decode_hex_key()?);
+        let x25519 = StaticSecret::from(*self.x25519_private.decode_hex_key()?);
+        let nostr = Keys::parse(self.nostr_private.expose()).map_err(|e| {
+            let err = format!("Keys parse failed: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        Ok(Identity { ed25519, x25519, nostr })
+    }
+
+    /// Decodes an opened keystore and checks that it was written for
+    /// `username`. Newer files are already bound to the username by the
+    /// keystore itself, which also covers a rename made from the duress
+    /// password that left the old name inside; older files are checked
+    /// against the name they carry.
+    fn from_keystore(unlocked: &Unlocked, username: &str) -> Result<Self, String> {
+        let mut login_data: LoginData = serde_json::from_slice(&unlocked.plaintext).map_err(|e| {
+            let err = format!("Deserialization failed: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        if unlocked.username_bound() {
+            login_data.username = username.to_string();
+        } else if !accounts::same_account(&login_data.username, username) {
+            error!("Keystore for {} belongs to {}", username, login_data.username);
+            return Err(format!("Keystore does not belong to account {}", username));
+        }
+        Ok(login_data)
+    }
+
+    /// Serializes the record for sealing into a keystore.
+    fn to_plaintext(&self) -> Result<Zeroizing<Vec<u8>>, String> {
+        let stored = StoredLoginData {
+            username: &self.username,
+            ed25519_private: self.ed25519_private.expose(),
+            x25519_private: self.x25519_private.expose(),
+            nostr_private: self.nostr_private.expose(),
+        };
+        serde_json::to_vec(&stored).map(Zeroizing::new).map_err(|e| {
+            let err = format!("Serialization failed: {}", e);
+            error!("{}", err);
+            err
+        })
+    }
+
+    /// Replaces the contents of an opened keystore with this login data.
+    fn write_into(&self, unlocked: &mut Unlocked) -> Result<(), String> {
+        let plaintext = self.to_plaintext()?;
+        unlocked.plaintext.fill(0);
+        unlocked.plaintext = plaintext.to_vec();
+        Ok(())
+    }
+}
+
+/// Writes a new keystore for `identity` and logs it in. Returns the
+/// recovery key, which is never stored in the clear and must be shown once.
+async fn store_new_account(
+    state: &AppState,
+    path: &Path,
+    password: &str,
+    username: &str,
+    identity: Identity,
+) -> Result<String, String> {
+    let mut recovery_key_bytes = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(recovery_key_bytes.as_mut_slice());
+    let recovery_key = general_purpose::STANDARD.encode(recovery_key_bytes.as_slice());
+    let plaintext = LoginData::from_identity(username, &identity)?.to_plaintext()?;
+    let (owned_username, password) = (username.to_string(), Zeroizing::new(password.to_string()));
+    let encrypted_data = blocking(move || keystore::seal(&plaintext, &owned_username, &password, &recovery_key_bytes)).await?;
+    keystore::save(path, &encrypted_data)?;
+    let session = Session::new(username, identity, &path.with_extension(""), &Settings::default());
+    start_session(state, session).await;
+    Ok(recovery_key)
+}
+
+#[tauri::command]
+async fn create_account(
+    state: tauri::State<'_, AppState>,
+    username: String,
+    password: String,
+    nostr_secret: Option<String>,
+    nostr_passphrase: Option<String>,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Creating account for username: {}", username);
+    if username.is_empty() || password.is_empty() {
+        error!("Create account failed: Username or password empty");
+        return Err("Username and password cannot be empty".to_string());
+    }
+    password::check(&password, &[&username])?;
+    let imported_keys = match nostr_secret.as_deref().filter(|s| !s.trim().is_empty()) {
+        Some(secret) => Some(identity::parse_nostr_secret(secret, nostr_passphrase.as_deref())?),
+        None => None,
+    };
+    let (username, path) = claim_account_path(&app_handle, &username)?;
+    let mnemonic = identity::generate_mnemonic()?;
+    let mut identity = identity::derive(&mnemonic)?;
+    if let Some(keys) = imported_keys {
+        info!("Using imported Nostr identity {}", keys.public_key());
+        identity.nostr = keys;
+        let recovery_key = store_new_account(&state, &path, &password, &username, identity).await?;
+        info!("Account created with imported Nostr key for username: {}", username);
+        // The recovery phrase cannot reproduce an imported key, so it is not
+        // offered; the encrypted keystore is the only backup of this identity.
+        return Ok(Response {
+            success: true,
+            message: "Account created with your existing Nostr key. Save your recovery key!".to_string(),
+            data: Some(json!({ "recovery_key": recovery_key }).to_string()),
+        });
+    }
+    let recovery_key = store_new_account(&state, &path, &password, &username, identity).await?;
     info!("Account created successfully for username: {}", username);
     Ok(Response {
         success: true,
-        message: "Account created. Save your recovery key!".to_string(),
-        data: Some(recovery_key),
+        message: "Account created. Save your recovery key and recovery phrase!".to_string(),
+        data: Some(json!({
+            "recovery_key": recovery_key,
+            "mnemonic": mnemonic.to_string()
+        }).to_string()),
+    })
+}
+
+/// Scores a candidate password so the UI can show feedback while typing.
+#[tauri::command]
+async fn check_password_strength(password: String, username: String) -> Result<Response, String> {
+    let strength = password::strength(&password, &[&username]);
+    Ok(Response {
+        success: strength.acceptable,
+        message: if strength.acceptable { "Strong enough".to_string() } else { strength.feedback.clone() },
+        data: Some(json!(strength).to_string()),
+    })
+}
+
+#[tauri::command]
+async fn restore_account(
+    state: tauri::State<'_, AppState>,
+    username: String,
+    password: String,
+    mnemonic: String,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Restoring account from recovery phrase for username: {}", username);
+    if username.is_empty() || password.is_empty() {
+        error!("Restore account failed: Username or password empty");
+        return Err("Username and password cannot be empty".to_string());
+    }
+    password::check(&password, &[&username])?;
+    let mnemonic = identity::parse_mnemonic(&mnemonic)?;
+    let (username, path) = claim_account_path(&app_handle, &username)?;
+    let identity = identity::derive(&mnemonic)?;
+    let recovery_key = store_new_account(&state, &path, &password, &username, identity).await?;
+    info!("Account restored successfully for username: {}", username);
+    Ok(Response {
+        success: true,
+        message: "Account restored. Save your new recovery key!".to_string(),
+        data: Some(json!({ "recovery_key": recovery_key }).to_string()),
     })
 }
 
@@ -150,162 +750,753 @@ async fn login(
     username: String,
     password: String,
     recovery_key: Option<String>,
+    recovery_shares: Option<Vec<String>>,
     app_handle: tauri::AppHandle
 ) -> Result<Response, String> {
     info!("Attempting login for username: {}", username);
-    debug!("Password provided: {}, Recovery key: {:?}", !password.is_empty(), recovery_key);
+    debug!("Password provided: {}, Recovery key provided: {}, Recovery shares provided: {}",
+        !password.is_empty(), recovery_key.is_some(), recovery_shares.as_ref().map_or(0, Vec::len));
     if username.is_empty() {
         error!("Login failed: Username is empty");
         return Err("Username cannot be empty".to_string());
     }
-    let path = get_account_path(&app_handle, &username)?;
-    debug!("Checking if account exists at: {:?}", path);
-    if !path.exists() {
-        error!("Login failed: Account does not exist at {:?}", path);
-        return Err("Account does not exist".to_string());
+    // Shares stand in for the recovery key they rebuild.
+    let recovery_key = match recovery_shares.filter(|shares| !shares.is_empty()) {
+        Some(shares) => Some(general_purpose::STANDARD.encode(shares::combine(&shares)?.as_slice())),
+        None => recovery_key,
+    };
+    let (username, path) = get_account_path(&app_handle, &username)?;
+    let password_login = recovery_key.is_none();
+    let mut unlocked = open_keystore(&path, &username, &password, recovery_key.as_deref(), "login").await?;
+    debug!("Keystore decrypted");
+    let mut login_data = LoginData::from_keystore(&unlocked, &username)?;
+    let identity = login_data.identity()?;
+    let mut new_recovery_key = None;
+    let data_dir = path.with_extension("");
+    // Older files may spell the username differently from the index.
+    login_data.username = username.clone();
+    let new_password = password_login.then_some(password.as_str());
+    if unlocked.outdated() {
+        info!("Rewriting keystore for {} as v{}", username, keystore::FORMAT_VERSION);
+        let upgraded = login_data.write_into(&mut unlocked)
+            .and_then(|_| rewrite_keystore(&path, &username, &unlocked, new_password));
+        match upgraded {
+            Ok(recovery_key) => new_recovery_key = recovery_key,
+            Err(e) => error!("Keystore upgrade failed, keeping old file: {}", e),
+        }
+    }
+    debug!("Keys decoded for {}", login_data.username);
+    let settings = Settings::load(&data_dir).unwrap_or_default();
+    start_session(&state, Session::new(&username, identity, &data_dir, &settings)).await;
+    info!("Login successful for username: {}", username);
+    if new_recovery_key.is_some() {
+        return Ok(Response {
+            success: true,
+            message: "Login successful. Your account was upgraded. Save your new recovery key!".to_string(),
+            data: new_recovery_key,
+        });
+    }
+    Ok(Response {
+        success: true,
+        message: "Login successful".to_string(),
+        data: None,
+    })
+}
+
+#[tauri::command]
+async fn logout(state: tauri::State<'_, AppState>) -> Result<Response, String> {
+    info!("Logging out");
+    end_session(&state, false).await;
+    Ok(Response {
+        success: true,
+        message: "Logged out".to_string(),
+        data: None,
+    })
+}
+
+#[tauri::command]
+async fn set_auto_lock(
+    state: tauri::State<'_, AppState>,
+    minutes: u32,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Setting auto-lock to {} minutes", minutes);
+    let session = current_session(&state).await?;
+    let data_dir = get_account_data_dir(&app_handle, &session.username)?;
+    let mut settings = Settings::load(&data_dir)?;
+    settings.auto_lock_minutes = minutes;
+    settings.save(&data_dir)?;
+    if let Some(current) = state.login.lock().unwrap().as_mut() {
+        current.auto_lock = settings.auto_lock();
+    }
+    let message = match minutes {
+        0 => "Auto-lock disabled".to_string(),
+        _ => format!("Auto-lock after {} idle minutes", minutes),
+    };
+    Ok(Response { success: true, message, data: None })
+}
+
+#[tauri::command]
+async fn set_recovery_key_threshold(
+    state: tauri::State<'_, AppState>,
+    failures: u32,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Requiring the recovery key after {} failed unlocks", failures);
+    let session = current_session(&state).await?;
+    let data_dir = get_account_data_dir(&app_handle, &session.username)?;
+    let mut settings = Settings::load(&data_dir)?;
+    settings.recovery_key_after_failures = failures;
+    settings.save(&data_dir)?;
+    let message = match failures {
+        0 => "The password is never locked out".to_string(),
+        _ => format!("The recovery key is required after {} failed attempts", failures),
+    };
+    Ok(Response { success: true, message, data: None })
+}
+
+#[tauri::command]
+async fn get_settings(
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    let session = current_session(&state).await?;
+    let settings = Settings::load(&get_account_data_dir(&app_handle, &session.username)?)?;
+    debug!("Settings for {}: {:?}", session.username, settings);
+    Ok(Response {
+        success: true,
+        message: "Settings retrieved".to_string(),
+        data: Some(json!(settings).to_string()),
+    })
+}
+
+/// Returns the unlock audit trail of the logged-in account, newest first.
+#[tauri::command]
+async fn get_login_history(
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Fetching login history");
+    let session = current_session(&state).await?;
+    let data_dir = get_account_data_dir(&app_handle, &session.username)?;
+    let history = {
+        let _guard = attempts::lock();
+        LoginAttempts::load(&data_dir)?.history
+    };
+    let failed = history.iter().filter(|entry| !entry.success).count();
+    let history: Vec<_> = history.into_iter().rev().collect();
+    Ok(Response {
+        success: true,
+        message: format!("{} recorded attempts, {} failed", history.len(), failed),
+        data: Some(json!(history).to_string()),
+    })
+}
+
+#[tauri::command]
+async fn change_password(
+    username: String,
+    password: String,
+    recovery_key: Option<String>,
+    new_password: String,
+    rotate_recovery_key: bool,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Changing password for username: {}", username);
+    if username.is_empty() || new_password.is_empty() {
+        error!("Change password failed: Username or new password empty");
+        return Err("Username and new password cannot be empty".to_string());
+    }
+    password::check(&new_password, &[&username, &password])?;
+    let (username, path) = get_account_path(&app_handle, &username)?;
+    let mut unlocked = open_keystore(&path, &username, &password, recovery_key.as_deref(), "change_password").await?;
+    debug!("Keystore decrypted for password change");
+    let mut login_data = LoginData::from_keystore(&unlocked, &username)?;
+    login_data.username = username.clone();
+    login_data.write_into(&mut unlocked)?;
+    let mut recovery_key_bytes = None;
+    if rotate_recovery_key || !unlocked.has_recovery() {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        recovery_key_bytes = Some(bytes);
+    }
+    let encrypted_data = {
+        let (username, new_password) = (username.clone(), Zeroizing::new(new_password));
+        let new_recovery_key_bytes = recovery_key_bytes.map(Zeroizing::new);
+        blocking(move || keystore::reseal(&unlocked, &username, Some(&new_password), new_recovery_key_bytes.as_deref())).await?
+    };
+    keystore::save(&path, &encrypted_data)?;
+    let new_recovery_key = recovery_key_bytes.as_mut().map(|bytes| {
+        let encoded = general_purpose::STANDARD.encode(&bytes);
+        bytes.fill(0);
+        encoded
+    });
+    info!("Password changed for username: {}", username);
+    if new_recovery_key.is_some() {
+        return Ok(Response {
+            success: true,
+            message: "Password changed. Save your new recovery key!".to_string(),
+            data: new_recovery_key,
+        });
+    }
+    Ok(Response {
+        success: true,
+        message: "Password changed".to_string(),
+        data: None,
+    })
+}
+
+/// Adds a second password that opens a decoy account with fresh keys and no
+/// contacts, for when the user is forced to unlock. With `wipe`, unlocking
+/// with it also destroys the real account. Setting it again replaces the
+/// decoy.
+///
+/// The keystore looks the same with or without a decoy, so the attempt is
+/// recorded as a password change to keep the audit trail from giving it away.
+/// The account's data directory does not: once the decoy has been used, its
+/// own sealed session, prekey and contact files sit next to the real
+/// account's, and two sets of them show that a second identity exists. They
+/// cannot be read without its keys, and a wiping decoy shreds the real set.
+#[tauri::command]
+async fn set_duress_password(
+    username: String,
+    password: String,
+    duress_password: String,
+    wipe: bool,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Setting duress password for username: {}", username);
+    if username.is_empty() || duress_password.is_empty() {
+        error!("Set duress password failed: Username or duress password empty");
+        return Err("Username and duress password cannot be empty".to_string());
+    }
+    password::check(&duress_password, &[&username, &password])?;
+    let (username, path) = get_account_path(&app_handle, &username)?;
+    let mut unlocked = open_keystore(&path, &username, &password, None, "change_password").await?;
+    LoginData::from_keystore(&unlocked, &username)?;
+    let decoy_identity = identity::derive(&identity::generate_mnemonic()?)?;
+    let decoy = LoginData::from_identity(&username, &decoy_identity)?.to_plaintext()?;
+    let owned_username = username.clone();
+    let new_recovery_key = blocking(move || {
+        unlocked.set_decoy(&duress_password, &decoy, wipe)?;
+        rewrite_keystore(&path, &owned_username, &unlocked, Some(&password))
+    })
+    .await?;
+    info!("Duress password set for username: {}", username);
+    Ok(Response {
+        success: true,
+        message: if wipe {
+            "Duress password set. Using it will erase this account.".to_string()
+        } else {
+            "Duress password set".to_string()
+        },
+        data: new_recovery_key,
+    })
+}
+
+/// Removes the duress password and its decoy account.
+#[tauri::command]
+async fn remove_duress_password(
+    username: String,
+    password: String,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Removing duress password for username: {}", username);
+    let (username, path) = get_account_path(&app_handle, &username)?;
+    let mut unlocked = open_keystore(&path, &username, &password, None, "change_password").await?;
+    LoginData::from_keystore(&unlocked, &username)?;
+    if unlocked.role() != keystore::Role::Primary {
+        error!("Remove duress password failed: not the primary password");
+        return Err("Decryption failed: wrong password or corrupted keystore".to_string());
+    }
+    let had_decoy = unlocked.has_decoy();
+    unlocked.clear_decoy();
+    let owned_username = username.clone();
+    let new_recovery_key = blocking(move || rewrite_keystore(&path, &owned_username, &unlocked, Some(&password))).await?;
+    info!("Duress password removed for username: {}", username);
+    Ok(Response {
+        success: true,
+        message: if had_decoy { "Duress password removed" } else { "No duress password was set" }.to_string(),
+        data: new_recovery_key,
+    })
+}
+
+/// Splits the recovery key into `shares` shares for friends or separate
+/// places, any `threshold` of which rebuild it at login.
+#[tauri::command]
+async fn split_recovery_key(
+    state: tauri::State<'_, AppState>,
+    recovery_key: String,
+    shares: u8,
+    threshold: u8,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Splitting recovery key into {} shares, {} needed", shares, threshold);
+    let session = current_session(&state).await?;
+    let (username, path) = get_account_path(&app_handle, &session.username)?;
+    // Check the key against the keystore, so shares of a mistyped key are
+    // never handed out. This goes through the throttle like any unlock.
+    open_keystore(&path, &username, "", Some(&recovery_key), "split_recovery_key").await?;
+    let recovery_key_bytes = Zeroizing::new(decode_recovery_key(&recovery_key)?);
+    let secret = Zeroizing::new(<[u8; 32]>::try_from(recovery_key_bytes.as_slice()).map_err(|_| {
+        let err = "Invalid recovery key length".to_string();
+        error!("{}", err);
+        err
+    })?);
+    let account = shares::account_tag(&session.identity.nostr.public_key().to_bytes());
+    let exported = shares::split(&secret, account, shares, threshold)?;
+    info!("Recovery key split for {}", username);
+    Ok(Response {
+        success: true,
+        message: format!("Recovery key split into {} shares. Any {} of them can recover this account.", shares, threshold),
+        data: Some(json!({
+            "account": hex::encode(account),
+            "threshold": threshold,
+            "shares": exported
+        }).to_string()),
+    })
+}
+
+#[tauri::command]
+async fn export_account(
+    state: tauri::State<'_, AppState>,
+    destination: String,
+    recovery_key: Option<String>,
+    passphrase: Option<String>,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Exporting account bundle to {}", destination);
+    let username = current_session(&state).await?.username;
+    let (username, path) = get_account_path(&app_handle, &username)?;
+    let app_data = get_accounts_dir(&app_handle)?.parent().map(Path::to_path_buf).unwrap_or_default();
+    let destination = backup::destination(&destination, &app_data)?;
+    let passphrase = passphrase.filter(|p| !p.is_empty());
+    if let (None, Some(recovery_key)) = (&passphrase, recovery_key.as_deref()) {
+        // Check the key against the keystore, so a typo cannot produce a
+        // bundle that nobody can open. Done before reading any file, so a
+        // duress unlock has already wiped what it should.
+        open_keystore(&path, &username, "", Some(recovery_key), "export_account").await?;
+    }
+    let mut files = BTreeMap::new();
+    files.insert(backup::KEYSTORE_ENTRY.to_string(), read(&path).map_err(|e| {
+        let err = format!("File read failed: {}", e);
+        error!("{}", err);
+        err
+    })?);
+    let data_dir = get_account_data_dir(&app_handle, &username)?;
+    if data_dir.is_dir() {
+        collect_files(&data_dir, "data/", &mut files)?;
     }
-    let encrypted_data = read(&path).map_err(|e| {
+    // The unlock history and throttle belong to this device, not the account.
+    files.remove(&format!("data/{}", attempts::ATTEMPTS_FILE));
+    debug!("Bundling {} files for {}", files.len(), username);
+    let owned_username = username.clone();
+    let bundle = match (passphrase, recovery_key.as_deref()) {
+        (Some(passphrase), _) => {
+            let passphrase = Zeroizing::new(passphrase);
+            blocking(move || backup::seal(&owned_username, files, Unlock::Password(&passphrase))).await?
+        }
+        (None, Some(recovery_key)) => {
+            let recovery_key_bytes = Zeroizing::new(decode_recovery_key(recovery_key)?);
+            blocking(move || backup::seal(&owned_username, files, Unlock::RecoveryKey(&recovery_key_bytes))).await?
+        }
+        (None, None) => {
+            error!("Export failed: no recovery key or passphrase");
+            return Err("A recovery key or passphrase is required to export".to_string());
+        }
+    };
+    keystore::save(&destination, &bundle)?;
+    info!("Account bundle exported for {}", username);
+    Ok(Response {
+        success: true,
+        message: format!("Account exported to {}", destination.display()),
+        data: None,
+    })
+}
+
+#[tauri::command]
+async fn import_account(
+    source: String,
+    username: String,
+    password: String,
+    recovery_key: Option<String>,
+    passphrase: Option<String>,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Importing account bundle from {} as {}", source, username);
+    if username.is_empty() {
+        error!("Import failed: Username is empty");
+        return Err("Username cannot be empty".to_string());
+    }
+    let username = accounts::normalize_username(&username)?;
+    let data = read(&source).map_err(|e| {
         let err = format!("File read failed: {}", e);
         error!("{}", err);
         err
     })?;
-    debug!("Encrypted data length: {}", encrypted_data.len());
-    if encrypted_data.len() < 2 {
-        error!("Login failed: Invalid encrypted data length");
-        return Err("Invalid encrypted data".to_string());
-    }
-    let salt_len = u16::from_be_bytes([encrypted_data[0], encrypted_data[1]]) as usize;
-    debug!("Salt length: {}, Total data length: {}", salt_len, encrypted_data.len());
-    if encrypted_data.len() < 2 + salt_len + 12 {
-        error!("Login failed: Invalid encrypted data format");
-        return Err("Invalid encrypted data format".to_string());
-    }
-    let salt_bytes = &encrypted_data[2..2 + salt_len];
-    let nonce_bytes = &encrypted_data[2 + salt_len..2 + salt_len + 12];
-    let ciphertext = &encrypted_data[2 + salt_len + 12..];
-    let mut key_bytes = if let Some(recovery_key) = recovery_key {
-        let recovery_key_bytes = general_purpose::STANDARD.decode(&recovery_key)
-            .map_err(|e| {
-                let err = format!("Invalid recovery key: {}", e);
+    let recovery_key_bytes = recovery_key.as_deref().map(decode_recovery_key).transpose()?.map(Zeroizing::new);
+    let password = Zeroizing::new(password);
+    let owned_password = password.clone();
+    let (bundle, mut unlocked) = blocking(move || {
+        let bundle = match (passphrase.as_deref().filter(|p| !p.is_empty()), recovery_key_bytes.as_deref()) {
+            (Some(passphrase), _) => backup::open(&data, Unlock::Password(passphrase)),
+            (None, Some(recovery_key)) => backup::open(&data, Unlock::RecoveryKey(recovery_key)),
+            (None, None) => Err("A recovery key or passphrase is required to import".to_string()),
+        };
+        let mut bundle = bundle?;
+        debug!("Bundle from {} created at {}", bundle.manifest.username, bundle.manifest.created_at);
+        let keystore_data = bundle.files.remove(backup::KEYSTORE_ENTRY).unwrap_or_default();
+        // The bundled keystore is still bound to the username it was
+        // exported under, which the manifest records.
+        let source_username = &bundle.manifest.username;
+        let unlocked = match (owned_password.is_empty(), recovery_key_bytes.as_deref()) {
+            (true, Some(recovery_key)) => keystore::open(&keystore_data, source_username, Unlock::RecoveryKey(recovery_key))?,
+            _ => keystore::open(&keystore_data, source_username, Unlock::Password(&owned_password))?,
+        };
+        Ok((bundle, unlocked))
+    })
+    .await?;
+    let mut login_data = LoginData::from_keystore(&unlocked, &bundle.manifest.username)?;
+    // Rebind the keystore to the username chosen on this device.
+    login_data.username = username.clone();
+    login_data.write_into(&mut unlocked)?;
+    let password = (!password.is_empty()).then_some(password);
+    // Nothing is claimed in the index until the whole account has been
+    // written next to it, so a failed import leaves no half-made account.
+    let accounts_dir = get_accounts_dir(&app_handle)?;
+    let mut suffix = [0u8; 8];
+    OsRng.fill_bytes(&mut suffix);
+    let staging = accounts_dir.join(format!(".import-{}", hex::encode(suffix)));
+    let staged = {
+        let (staging, username) = (staging.clone(), username.clone());
+        blocking(move || stage_import(&staging, &username, &unlocked, password.as_deref().map(String::as_str), &bundle.files)).await
+    };
+    let imported = staged.and_then(|new_recovery_key| {
+        let (username, path) = claim_account_path(&app_handle, &username)?;
+        if let Err(err) = install_import(&staging, &path) {
+            if let Err(e) = accounts::remove(&accounts_dir, &username) {
+                error!("Failed to release index entry of {}: {}", username, e);
+            }
+            return Err(err);
+        }
+        Ok((username, new_recovery_key))
+    });
+    if staging.exists() {
+        if let Err(e) = shred_dir(&staging) {
+            warn!("Failed to clean up import staging {:?}: {}", staging, e);
+        }
+    }
+    let (username, new_recovery_key) = imported?;
+    info!("Account imported as {}", username);
+    if new_recovery_key.is_some() {
+        return Ok(Response {
+            success: true,
+            message: "Account imported. Save your new recovery key!".to_string(),
+            data: new_recovery_key,
+        });
+    }
+    Ok(Response {
+        success: true,
+        message: "Account imported".to_string(),
+        data: None,
+    })
+}
+
+#[tauri::command]
+async fn list_accounts(app_handle: tauri::AppHandle) -> Result<Response, String> {
+    info!("Listing local accounts");
+    let accounts_dir = get_accounts_dir(&app_handle)?;
+    let usernames = accounts::list(&accounts_dir)?;
+    debug!("Found {} accounts", usernames.len());
+    Ok(Response {
+        success: true,
+        message: format!("{} accounts on this device", usernames.len()),
+        data: Some(json!(usernames).to_string()),
+    })
+}
+
+#[tauri::command]
+async fn rename_account(
+    state: tauri::State<'_, AppState>,
+    username: String,
+    new_username: String,
+    password: String,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Renaming account {} to {}", username, new_username);
+    if username.is_empty() || new_username.is_empty() {
+        error!("Rename failed: Username empty");
+        return Err("Usernames cannot be empty".to_string());
+    }
+    let accounts_dir = get_accounts_dir(&app_handle)?;
+    let account = accounts::find(&accounts_dir, &username)?;
+    let new_username = accounts::normalize_username(&new_username)?;
+    let taken = accounts::find(&accounts_dir, &new_username)
+        .is_ok_and(|other| other.id != account.id);
+    if taken {
+        error!("Rename failed: Username {} already exists", new_username);
+        return Err("Username already exists on this device.".to_string());
+    }
+    let path = account.keystore_path(&accounts_dir);
+    let mut unlocked = open_keystore(&path, &account.username, &password, None, "rename_account").await?;
+    let mut login_data = LoginData::from_keystore(&unlocked, &account.username)?;
+    login_data.username = new_username.clone();
+    login_data.write_into(&mut unlocked)?;
+    // The file id stays the same, so only the keystore contents and the index
+    // entry change.
+    let new_recovery_key = {
+        let (path, new_username) = (path.clone(), new_username.clone());
+        blocking(move || rewrite_keystore(&path, &new_username, &unlocked, Some(&password))).await?
+    };
+    accounts::rename_entry(&accounts_dir, &account.username, &new_username)?;
+    if let Ok(mut guard) = state.login.lock() {
+        if let Some(current) = guard.as_mut().filter(|current| current.username == account.username) {
+            current.username = new_username.clone();
+        }
+    }
+    if let Ok(mut locked) = state.locked.lock() {
+        if let Some(current) = locked.as_mut().filter(|current| **current == account.username) {
+            *current = new_username.clone();
+        }
+    }
+    info!("Account {} renamed to {}", username, new_username);
+    if new_recovery_key.is_some() {
+        return Ok(Response {
+            success: true,
+            message: "Account renamed. Save your new recovery key!".to_string(),
+            data: new_recovery_key,
+        });
+    }
+    Ok(Response {
+        success: true,
+        message: format!("Account renamed to {}", new_username),
+        data: None,
+    })
+}
+
+/// Shreds the account's keystore and data directory and drops it from the
+/// index.
+///
+/// Done with the duress password, this deletes the real account too, even
+/// when the duress password was set up without `wipe`: the decoy lives in the
+/// same file, and an account that stayed listed or left its keystore behind
+/// would show that it was only the decoy that went away.
+#[tauri::command]
+async fn delete_account(
+    state: tauri::State<'_, AppState>,
+    username: String,
+    password: String,
+    app_handle: tauri::AppHandle
+) -> Result<Response, String> {
+    info!("Deleting account {}", username);
+    if username.is_empty() || password.is_empty() {
+        error!("Delete failed: Username or password empty");
+        return Err("Username and password cannot be empty".to_string());
+    }
+    let accounts_dir = get_accounts_dir(&app_handle)?;
+    let account = accounts::find(&accounts_dir, &username)?;
+    let path = account.keystore_path(&accounts_dir);
+    // Only proves the password; the contents are thrown away.
+    let unlocked = open_keystore(&path, &account.username, &password, None, "delete_account").await?;
+    LoginData::from_keystore(&unlocked, &account.username)?;
+    let data_dir = account.data_dir(&accounts_dir);
+    if data_dir.exists() {
+        shred_dir(&data_dir)?;
+    }
+    keystore::shred(&path)?;
+    accounts::remove(&accounts_dir, &account.username)?;
+    let logged_in = state.login.lock().unwrap().as_ref().is_some_and(|current| current.username == account.username);
+    let locked = state.locked.lock().unwrap().as_ref().is_some_and(|current| *current == account.username);
+    if logged_in || locked {
+        end_session(&state, false).await;
+    }
+    info!("Account {} deleted", username);
+    Ok(Response {
+        success: true,
+        message: format!("Account {} deleted", username),
+        data: None,
+    })
+}
+
+#[tauri::command]
+async fn debug_login_state(state: tauri::State<'_, AppState>) -> Result<Response, String> {
+    info!("Checking login state");
+    let session = current_session(&state).await?;
+    info!("Login state: User {} is logged in", session.username);
+    Ok(Response {
+        success: true,
+        message: format!("Logged in as {}", session.username),
+        data: Some(session.username.clone()),
+    })
+}
+
+#[tauri::command]
+async fn get_user_info(
+    state: tauri::State<'_, AppState>,
+) -> Result<Response, String> {
+    info!("Fetching user info");
+    let session = current_session(&state).await?;
+    debug!("Session retrieved: username={}", session.username);
+    let x25519_public = PublicKey::from(&session.identity.x25519);
+    debug!("X25519 public key generated");
+    let response = Response {
+        success: true,
+        message: "User info retrieved".to_string(),
+        data: Some(json!({
+            "username": session.username,
+            "nostr_public": session.identity.nostr.public_key().to_bech32().map_err(|e| {
+                let err = format!("Bech32 encode failed: {}", e);
                 error!("{}", err);
                 err
-            })?;
-        debug!("Recovery key bytes length: {}", recovery_key_bytes.len());
-        if recovery_key_bytes.len() != 32 {
-            error!("Login failed: Invalid recovery key length");
-            return Err("Invalid recovery key length".to_string());
-        }
-        recovery_key_bytes
-    } else {
-        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(65536, 2, 1, Some(32)).unwrap());
-        let mut key = [0u8; 32];
-        argon2.hash_password_into(password.as_bytes(), salt_bytes, &mut key).map_err(|e| {
-            let err = e.to_string();
-            error!("{}", err);
-            err
-        })?;
-        debug!("Password hashed successfully");
-        key.to_vec()
+            })?,
+            "x25519_public": hex::encode(x25519_public.to_bytes()),
+            "auto_lock_minutes": session.auto_lock.map(|limit| limit.as_secs() / 60).unwrap_or(0)
+        }).to_string()),
     };
-    let cipher = Aes256Gcm::new(&Key::<Aes256Gcm>::from_slice(&key_bytes));
-    let nonce = Nonce::from_slice(nonce_bytes);
-    debug!("Attempting decryption...");
-    let plaintext = cipher.decrypt(nonce, ciphertext).map_err(|e| {
-        let err = format!("Decryption failed: {}", e);
+    debug!("User info response: {:?}", response);
+    info!("User info retrieved successfully");
+    Ok(response)
+}
+
+#[tauri::command]
+async fn export_nostr_key(
+    state: tauri::State<'_, AppState>,
+    passphrase: String,
+) -> Result<Response, String> {
+    info!("Exporting Nostr key as ncryptsec");
+    if passphrase.is_empty() {
+        error!("Export failed: Passphrase empty");
+        return Err("Passphrase cannot be empty".to_string());
+    }
+    let session = current_session(&state).await?;
+    let ncryptsec = identity::encrypt_ncryptsec(&session.identity.nostr, &passphrase)?;
+    info!("Nostr key exported for {}", session.username);
+    Ok(Response {
+        success: true,
+        message: "Nostr key exported as ncryptsec".to_string(),
+        data: Some(ncryptsec),
+    })
+}
+
+/// Parses a contact's Nostr key and returns it with its npub form, which
+/// the trust store is keyed by.
+fn parse_contact(npub: &str) -> Result<(nostr_sdk::PublicKey, String), String> {
+    let public_key = nostr_sdk::PublicKey::parse(npub.trim()).map_err(|e| {
+        let err = format!("Invalid contact key: {}", e);
         error!("{}", err);
         err
     })?;
-    let login_data: LoginData = serde_json::from_slice(&plaintext).map_err(|e| {
-        let err = format!("Deserialization failed: {}", e);
+    let npub = public_key.to_bech32().map_err(|e| {
+        let err = format!("Bech32 encode failed: {}", e);
         error!("{}", err);
         err
     })?;
-    debug!("Login data deserialized: {:?}", login_data.username);
-    state.login.lock().unwrap().replace(login_data.clone());
-    key_bytes.fill(0);
-    info!("Login successful for username: {}", username);
-    Ok(Response {
-        success: true,
-        message: "Login successful".to_string(),
-        data: None,
-    })
+    Ok((public_key, npub))
 }
 
 #[tauri::command]
-async fn debug_login_state(state: tauri::State<'_, AppState>) -> Result<Response, String> {
-    info!("Checking login state");
-    let login_data = match state.login.lock() {
-        Ok(guard) => guard.as_ref().ok_or_else(|| {
-            let err = "No user logged in".to_string();
-            error!("{}", err);
-            err
-        })?.clone(),
-        Err(e) => {
-            let err = format!("Failed to lock login state: {}", e);
-            error!("{}", err);
-            return Err(err);
-        }
-    };
-    info!("Login state: User {} is logged in", login_data.username);
+async fn list_contacts(state: tauri::State<'_, AppState>) -> Result<Response, String> {
+    let session = current_session(&state).await?;
+    let contacts: Vec<_> = session.lock_contacts()?.contacts().iter()
+        .map(|(npub, contact)| json!({
+            "npub": npub,
+            "x_pub": contact.x_pub,
+            "first_seen": contact.first_seen,
+            "verified": contact.verified,
+            "pending_key": contact.pending_key,
+            "format": contact.format,
+            "gift_wrap": contact.gift_wrap
+        }))
+        .collect();
     Ok(Response {
         success: true,
-        message: format!("Logged in as {}", login_data.username),
-        data: Some(login_data.username.clone()),
+        message: format!("{} contacts", contacts.len()),
+        data: Some(json!(contacts).to_string()),
     })
 }
 
+/// Safety number of the conversation with a contact, to compare in person
+/// or by scanning the QR payload before marking the contact verified.
 #[tauri::command]
-async fn get_user_info(
-    state: tauri::State<'_, AppState>,
-) -> Result<Response, String> {
-    info!("Fetching user info");
-    let login_data = match state.login.lock() {
-        Ok(guard) => guard.as_ref().ok_or_else(|| {
-            let err = "Not logged in".to_string();
-            error!("{}", err);
-            err
-        })?.clone(),
-        Err(e) => {
-            let err = format!("Failed to lock login state: {}", e);
-            error!("{}", err);
-            return Err(err);
-        }
-    };
-    debug!("Login data retrieved: username={}", login_data.username);
-    let nostr_keys = Keys::parse(&login_data.nostr_private).map_err(|e| {
-        let err = format!("Keys parse failed: {}", e);
-        error!("{}", err);
-        err
-    })?;
-    debug!("Nostr keys parsed successfully");
-    let x25519_secret = hex::decode(&login_data.x25519_private).map_err(|e| {
-        let err = format!("X25519 decode failed: {}", e);
+async fn get_safety_number(state: tauri::State<'_, AppState>, npub: String) -> Result<Response, String> {
+    let session = current_session(&state).await?;
+    let (their_nostr, npub) = parse_contact(&npub)?;
+    let contact = session.lock_contacts()?.get(&npub).filter(|contact| !contact.x_pub.is_empty()).cloned().ok_or_else(|| {
+        let err = format!("No key seen yet for {}", npub);
         error!("{}", err);
         err
     })?;
-    let x25519_secret: [u8; 32] = x25519_secret.try_into().map_err(|e| {
-        let err = format!("Invalid x25519 private key: {:?}", e);
+    let their_x_pub = <[u8; 32]>::try_from(hex::decode(&contact.x_pub).unwrap_or_default()).map_err(|_| {
+        let err = "Invalid pinned key".to_string();
         error!("{}", err);
         err
     })?;
-    let x25519_public = PublicKey::from(&StaticSecret::from(x25519_secret));
-    debug!("X25519 public key generated");
-    let response = Response {
+    let (digits, qr) = trust::safety_number(
+        (&session.identity.nostr.public_key().to_bytes(), &PublicKey::from(&session.identity.x25519)),
+        (&their_nostr.to_bytes(), &PublicKey::from(their_x_pub)),
+    );
+    Ok(Response {
         success: true,
-        message: "User info retrieved".to_string(),
+        message: "Safety number computed".to_string(),
         data: Some(json!({
-            "username": login_data.username,
-            "nostr_public": nostr_keys.public_key().to_bech32().map_err(|e| {
-                let err = format!("Bech32 encode failed: {}", e);
-                error!("{}", err);
-                err
-            })?,
-            "x25519_public": hex::encode(x25519_public.to_bytes())
+            "npub": npub,
+            "safety_number": digits,
+            "qr": qr,
+            "verified": contact.verified,
+            "pending_key": contact.pending_key
         }).to_string()),
-    };
-    debug!("User info response: {:?}", response);
-    info!("User info retrieved successfully");
-    Ok(response)
+    })
+}
+
+#[tauri::command]
+async fn set_contact_verified(state: tauri::State<'_, AppState>, npub: String, verified: bool) -> Result<Response, String> {
+    let session = current_session(&state).await?;
+    let (_, npub) = parse_contact(&npub)?;
+    session.lock_contacts()?.set_verified(&npub, verified)?;
+    Ok(Response {
+        success: true,
+        message: if verified { "Contact verified" } else { "Contact no longer verified" }.to_string(),
+        data: None,
+    })
+}
+
+/// Accepts the new key of a verified contact after a blocking key change.
+#[tauri::command]
+async fn accept_contact_key(state: tauri::State<'_, AppState>, npub: String) -> Result<Response, String> {
+    let session = current_session(&state).await?;
+    let (_, npub) = parse_contact(&npub)?;
+    session.lock_contacts()?.accept_key_change(&npub)?;
+    Ok(Response {
+        success: true,
+        message: "New key accepted. Compare safety numbers again to verify it.".to_string(),
+        data: None,
+    })
+}
+
+/// Chooses the format messages to a contact are sent in, for contacts on
+/// other Nostr clients.
+#[tauri::command]
+async fn set_contact_format(state: tauri::State<'_, AppState>, npub: String, format: Format) -> Result<Response, String> {
+    let session = current_session(&state).await?;
+    let (_, npub) = parse_contact(&npub)?;
+    session.lock_contacts()?.set_format(&npub, format)?;
+    Ok(Response {
+        success: true,
+        message: "Contact format updated".to_string(),
+        data: None,
+    })
+}
+
+/// Turns gift wrapping (NIP-59) of messages to a contact on or off.
+#[tauri::command]
+async fn set_contact_gift_wrap(state: tauri::State<'_, AppState>, npub: String, gift_wrap: bool) -> Result<Response, String> {
+    let session = current_session(&state).await?;
+    let (_, npub) = parse_contact(&npub)?;
+    session.lock_contacts()?.set_gift_wrap(&npub, gift_wrap)?;
+    Ok(Response {
+        success: true,
+        message: if gift_wrap { "Messages to this contact will be gift wrapped" } else { "Messages to this contact will not be gift wrapped" }.to_string(),
+        data: None,
+    })
 }
 
 #[tauri::command]
@@ -313,27 +1504,10 @@ async fn init_nostr_client(
     state: tauri::State<'_, AppState>,
 ) -> Result<Response, String> {
     info!("Initializing Nostr client");
-    let login_data = match state.login.lock() {
-        Ok(guard) => guard.as_ref().ok_or_else(|| {
-            let err = "Not logged in".to_string();
-            error!("{}", err);
-            err
-        })?.clone(),
-        Err(e) => {
-            let err = format!("Failed to lock login state: {}", e);
-            error!("{}", err);
-            return Err(err);
-        }
-    };
-    debug!("Login data retrieved for Nostr client: username={}", login_data.username);
-    let nostr_keys = Keys::parse(&login_data.nostr_private).map_err(|e| {
-        let err = e.to_string();
-        error!("Keys parse failed: {}", err);
-        err
-    })?;
-    debug!("Nostr keys parsed successfully");
+    let session = current_session(&state).await?;
+    debug!("Session retrieved for Nostr client: username={}", session.username);
     let opts = Options::new().timeout(Duration::from_secs(30));
-    let client = Client::with_opts(nostr_keys, opts);
+    let client = Client::with_opts(session.identity.nostr.clone(), opts);
     debug!("Nostr client created with options");
     if let Err(e) = client.add_relay("wss://relay.damus.io").await {
         let err = format!("Failed to add relay wss://relay.damus.io: {}", e);
@@ -350,112 +1524,341 @@ async fn init_nostr_client(
     client.connect().await;
     debug!("Nostr client connected to relays");
     state.nostr_client.lock().unwrap().replace(client.clone());
+    let prekey_client = client.clone();
+    let task = spawn(async move {
+        let mut interval = tokio::time::interval(PREKEY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = publish_prekeys(&prekey_client, &session).await {
+                error!("Prekey maintenance failed: {}", e);
+            }
+        }
+    });
+    if let Some(old) = state.prekey_task.lock().unwrap().replace(task) {
+        old.abort();
+    }
     info!("Nostr client initialized successfully");
     Ok(Response { success: true, message: "Nostr client initialized".to_string(), data: None })
 }
 
+/// Rotates and tops up our prekeys as needed and publishes the bundle if it
+/// changed since it was last published in this run.
+async fn publish_prekeys(client: &Client, session: &Session) -> Result<(), String> {
+    let unpublished = {
+        let mut ratchets = session.lock_ratchets()?;
+        ratchets.prekeys.maintain()?;
+        ratchets.prekeys.unpublished(&session.identity.nostr.public_key().to_hex(), &session.identity.x25519, &session.identity.ed25519)
+    };
+    let Some((revision, bundle)) = unpublished else {
+        return Ok(());
+    };
+    let content = serde_json::to_string(&bundle).map_err(|e| {
+        let err = format!("Bundle serialization failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    let event = EventBuilder::new(Kind::ApplicationSpecificData, content, vec![Tag::identifier(prekeys::BUNDLE_IDENTIFIER)])
+        .sign_with_keys(&session.identity.nostr)
+        .map_err(|e| {
+            let err = format!("Bundle signing failed: {}", e);
+            error!("{}", err);
+            err
+        })?;
+    client.send_event(event).await.map_err(|e| {
+        let err = format!("Bundle publishing failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    session.lock_ratchets()?.prekeys.mark_published(revision);
+    info!("Published prekey bundle with {} one-time prekeys", bundle.opks.len());
+    Ok(())
+}
+
+/// Fetches the newest prekey bundle published by `author`, if any. Relays
+/// may return events they were never sent by `author`, so the signature is
+/// checked here rather than trusted.
+async fn fetch_prekey_bundle(client: &Client, author: nostr_sdk::PublicKey) -> Result<Option<PeerBundle>, String> {
+    let filter = Filter::new()
+        .kind(Kind::ApplicationSpecificData)
+        .author(author)
+        .identifier(prekeys::BUNDLE_IDENTIFIER);
+    let events = client.fetch_events(vec![filter], Some(BUNDLE_FETCH_TIMEOUT)).await.map_err(|e| {
+        let err = format!("Prekey bundle fetch failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    let newest = events.into_iter()
+        .filter(|event| event.pubkey == author && event.verify().is_ok())
+        .max_by_key(|event| event.created_at);
+    match newest {
+        Some(event) => prekeys::Bundle::parse(&event.content, &author.to_hex()).map(Some),
+        None => {
+            debug!("No prekey bundle published by {}", author);
+            Ok(None)
+        }
+    }
+}
+
+/// Tells the frontend that a contact's key changed. For a verified contact
+/// this is blocking: nothing is sent to or shown from them until the user
+/// accepts the new key.
+fn emit_key_changed<R: tauri::Runtime>(emitter: &impl Emitter<R>, npub: &str, blocking: bool) {
+    warn!("Key of {} changed{}", npub, if blocking { ", blocking until accepted" } else { "" });
+    if let Err(e) = emitter.emit("key_changed", json!({ "npub": npub, "blocking": blocking })) {
+        error!("Failed to emit key_changed: {}", e);
+    }
+}
+
+/// Result of checking the `x_pub` tag of a message against its author.
+enum SenderKey {
+    /// The key we already talk to the author with, or the one they announced.
+    Verified,
+    /// The author announced no key and we never talked to them.
+    Unannounced,
+    /// The author uses or announced a different key, or the key is that of
+    /// a session with someone else.
+    Mismatch,
+}
+
+/// Announced X25519 and ed25519 keys by author, with the time they were
+/// fetched. Authors without an announcement are kept too, without keys.
+type AnnouncedKeys = BTreeMap<nostr_sdk::PublicKey, (Instant, Option<PublicKey>, Option<VerifyingKey>)>;
+
+/// Checks that `x_pub` belongs to `author`. Anyone can put any key in the tag
+/// of their own events, so it is only trusted if it is the key of an existing
+/// conversation with `author` or the identity key of their prekey bundle,
+/// which `author` signed, and no other author has a session with it.
+async fn check_sender_key(client: &Client, session: &Session, announced: &mut AnnouncedKeys, author: nostr_sdk::PublicKey, npub: &str, x_pub: &PublicKey) -> SenderKey {
+    let (known, bound_elsewhere) = match session.lock_ratchets() {
+        Ok(ratchets) => (ratchets.peer_key(npub), ratchets.bound_elsewhere(npub, x_pub)),
+        Err(_) => return SenderKey::Unannounced,
+    };
+    if bound_elsewhere {
+        return SenderKey::Mismatch;
+    }
+    if known.as_ref() == Some(x_pub) {
+        return SenderKey::Verified;
+    }
+    let stale = match announced.get(&author) {
+        None => true,
+        Some((fetched, Some(key), _)) => key != x_pub && fetched.elapsed() >= ANNOUNCEMENT_REFRESH,
+        Some((fetched, None, _)) => fetched.elapsed() >= ANNOUNCEMENT_RETRY,
+    };
+    if stale {
+        let (key, signing_key) = match fetch_prekey_bundle(client, author).await {
+            Ok(Some(bundle)) => (Some(bundle.identity), Some(bundle.verifying_key)),
+            Ok(None) => (None, None),
+            Err(e) => {
+                error!("Could not fetch key announcement of {}: {}", npub, e);
+                (None, None)
+            }
+        };
+        announced.insert(author, (Instant::now(), key, signing_key));
+    }
+    match announced.get(&author).and_then(|(_, key, _)| *key).or(known) {
+        Some(key) if key == *x_pub => SenderKey::Verified,
+        Some(_) => SenderKey::Mismatch,
+        None => SenderKey::Unannounced,
+    }
+}
+
 #[tauri::command]
 async fn send_nostr_message(
     state: tauri::State<'_, AppState>,
     recipient_nostr_pub: String,
-    recipient_x_pub: String,
+    recipient_x_pub: Option<String>,
     text: String,
+    app_handle: tauri::AppHandle,
 ) -> Result<Response, String> {
     info!("Sending Nostr message to {}", recipient_nostr_pub);
-    let (sender_x_priv_hex, nostr_priv_hex) = {
-        let guard = state.login.lock().unwrap();
-        let login_data = guard.as_ref().ok_or_else(|| {
-            let err = "Not logged in".to_string();
-            error!("{}", err);
-            err
-        })?.clone();
-        (login_data.x25519_private, login_data.nostr_private)
-    };
-    debug!("Retrieved login data for sending message");
-    let sender_priv_bytes = hex::decode(&sender_x_priv_hex).map_err(|e| {
+    let session = current_session(&state).await?;
+    let identity = session.identity.clone();
+    debug!("Retrieved session for sending message");
+    let sender_secret = &identity.x25519;
+    let recip_nostr_pub = nostr_sdk::PublicKey::from_bech32(&recipient_nostr_pub).map_err(|e| {
         let err = e.to_string();
-        error!("Sender privkey decode failed: {}", err);
+        error!("Recipient Nostr pubkey parse failed: {}", err);
         err
     })?;
-    let sender_priv: [u8; 32] = sender_priv_bytes.try_into().map_err(|_| {
-        let err = "Invalid privkey".to_string();
+    debug!("Recipient Nostr public key parsed");
+    let client = state.nostr_client.lock().unwrap().clone().ok_or_else(|| {
+        let err = "No client".to_string();
         error!("{}", err);
         err
     })?;
-    let sender_secret = StaticSecret::from(sender_priv);
-    debug!("Sender X25519 secret generated");
-    let recip_x_pub_bytes = hex::decode(&recipient_x_pub).map_err(|e| {
-        let err = e.to_string();
-        error!("Recipient pubkey decode failed: {}", err);
+    let given_x_pub = match recipient_x_pub.as_deref().map(str::trim).filter(|x_pub| !x_pub.is_empty()) {
+        Some(x_pub) => {
+            let recip_x_pub_bytes = hex::decode(x_pub).map_err(|e| {
+                let err = e.to_string();
+                error!("Recipient pubkey decode failed: {}", err);
+                err
+            })?;
+            let recip_x_pub: [u8; 32] = recip_x_pub_bytes.try_into().map_err(|_| {
+                let err = "Invalid pubkey".to_string();
+                error!("{}", err);
+                err
+            })?;
+            debug!("Recipient X25519 public key parsed");
+            Some(PublicKey::from(recip_x_pub))
+        }
+        None => None,
+    };
+    let recipient_npub = recip_nostr_pub.to_bech32().map_err(|e| {
+        let err = format!("Bech32 encode failed: {}", e);
+        error!("{}", err);
         err
     })?;
-    let recip_x_pub: [u8; 32] = recip_x_pub_bytes.try_into().map_err(|_| {
-        let err = "Invalid pubkey".to_string();
+    let (format, gift_wrap) = session.lock_contacts()?.get(&recipient_npub)
+        .map(|contact| (contact.format, contact.gift_wrap))
+        .unwrap_or_default();
+    if format != Format::Dumbchat {
+        return send_nostr_dm(&client, &identity, recip_nostr_pub, format, &text).await;
+    }
+    // Without a session, the contact's prekey bundle starts one and also
+    // tells us their X25519 key, so an npub is enough.
+    let (known_x_pub, has_session) = {
+        let pinned = session.lock_contacts()?.get(&recipient_npub)
+            .and_then(|contact| hex::decode(&contact.x_pub).ok())
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .map(PublicKey::from);
+        let ratchets = session.lock_ratchets()?;
+        let known = given_x_pub.or(pinned).or_else(|| ratchets.peer_key(&recipient_npub));
+        (known, known.is_some_and(|x_pub| ratchets.has_session(&recipient_npub, &x_pub)))
+    };
+    let bundle = if has_session {
+        None
+    } else {
+        match fetch_prekey_bundle(&client, recip_nostr_pub).await {
+            Ok(bundle) => bundle,
+            Err(e) if known_x_pub.is_some() => {
+                error!("Continuing without prekey bundle: {}", e);
+                None
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    // The bundle is the contact's signed announcement of their key, so a
+    // key entered by hand must match it.
+    if let (Some(given), Some(bundle)) = (&given_x_pub, &bundle) {
+        if bundle.identity != *given {
+            error!("Prekey bundle of {} is for a different X25519 key", recipient_nostr_pub);
+            return Err("The X25519 key does not match the one this contact announced. Check it with them, or leave it empty to use the announced key.".to_string());
+        }
+    }
+    let recip_pubkey = given_x_pub.or(bundle.as_ref().map(|bundle| bundle.identity)).or(known_x_pub).ok_or_else(|| {
+        let err = "This contact has not published a prekey bundle. Enter their X25519 key to message them.".to_string();
         error!("{}", err);
         err
     })?;
-    let recip_pubkey = PublicKey::from(recip_x_pub);
-    debug!("Recipient X25519 public key parsed");
-    let shared_secret = sender_secret.diffie_hellman(&recip_pubkey);
-    let key = Key::<Aes256Gcm>::from_slice(shared_secret.as_bytes());
-    let cipher = Aes256Gcm::new(key);
-    debug!("Shared secret and cipher created");
-    let payload = json!({ "text": text });
-    let plaintext = serde_json::to_string(&payload).map_err(|e| {
+    let trust = session.lock_contacts()?.observe(&recipient_npub, &recip_pubkey)?;
+    if let Trust::Changed { verified } = trust {
+        emit_key_changed(&app_handle, &recipient_npub, verified);
+        if verified {
+            return Err(KEY_CHANGED_ERROR.to_string());
+        }
+    }
+    let content = Content::signed(&identity.ed25519, text, attempts::now(), recip_nostr_pub.to_hex());
+    let plaintext = serde_json::to_string(&content).map_err(|e| {
         let err = e.to_string();
         error!("Payload serialization failed: {}", err);
         err
     })?;
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    let ciphertext = cipher.encrypt(&nonce, plaintext.as_bytes()).map_err(|e| {
-        let err = e.to_string();
-        error!("Encryption failed: {}", err);
-        err
-    })?;
-    let enc_payload = EncryptedPayload {
-        ciphertext: general_purpose::STANDARD.encode(&ciphertext),
-        nonce: general_purpose::STANDARD.encode(nonce.as_slice()),
+    let enc_payload = {
+        let mut ratchets = session.lock_ratchets()?;
+        message::encrypt_ratchet(&mut ratchets, sender_secret, &recip_pubkey, &recipient_npub, bundle.as_ref(), plaintext.as_bytes())?
     };
+    debug!("Message encrypted with payload v{}", enc_payload.v);
     let enc_json = serde_json::to_string(&enc_payload).map_err(|e| {
         let err = e.to_string();
         error!("Payload serialization failed: {}", err);
         err
     })?;
     debug!("Encrypted payload created: {:?}", enc_payload);
-    let nostr_keys = Keys::parse(&nostr_priv_hex).map_err(|e| {
-        let err = e.to_string();
-        error!("Keys parse failed: {}", err);
-        err
-    })?;
-    let recip_nostr_pub = nostr_sdk::PublicKey::from_bech32(&recipient_nostr_pub).map_err(|e| {
-        let err = e.to_string();
-        error!("Recipient Nostr pubkey parse failed: {}", err);
-        err
-    })?;
-    debug!("Nostr keys and recipient public key parsed");
-    let sender_x_pub = hex::encode(PublicKey::from(&sender_secret).to_bytes());
-    let event = EventBuilder::new(Kind::EncryptedDirectMessage, enc_json, vec![Tag::public_key(recip_nostr_pub)])
-        .add_tags(vec![Tag::custom(TagKind::Custom(Cow::Owned("x_pub".to_string())), vec![sender_x_pub])])
-        .pow(16)
-        .sign_with_keys(&nostr_keys)
-        .map_err(|e| {
+    let sender_x_pub = hex::encode(PublicKey::from(sender_secret).to_bytes());
+    let builder = EventBuilder::new(Kind::EncryptedDirectMessage, enc_json, vec![Tag::public_key(recip_nostr_pub)])
+        .add_tags(vec![Tag::custom(TagKind::Custom(Cow::Owned("x_pub".to_string())), vec![sender_x_pub])]);
+    // A gift wrap is signed by a throwaway key, so proof of work on the
+    // hidden event would be wasted.
+    let builder = if gift_wrap { builder } else { builder.pow(16) };
+    publish_dm(&client, &identity, recip_nostr_pub, builder, gift_wrap).await?;
+    info!("Message sent successfully to {}", recipient_nostr_pub);
+    Ok(Response { success: true, message: "Sent via Nostr".to_string(), data: None })
+}
+
+/// Signs and sends a direct message event, or with `gift_wrap` seals it
+/// and sends it gift wrapped (NIP-59) instead. Relays then only see an
+/// event from a throwaway key, at a randomized time, tagged with the
+/// recipient.
+async fn publish_dm(client: &Client, identity: &Identity, recipient: nostr_sdk::PublicKey, builder: EventBuilder, gift_wrap: bool) -> Result<(), String> {
+    let event = if gift_wrap {
+        let rumor = builder.build(identity.nostr.public_key());
+        EventBuilder::gift_wrap(&identity.nostr, &recipient, rumor, None).await.map_err(|e| {
+            let err = format!("Gift wrapping failed: {}", e);
+            error!("{}", err);
+            err
+        })?
+    } else {
+        builder.sign_with_keys(&identity.nostr).map_err(|e| {
             let err = e.to_string();
             error!("Event signing failed: {}", err);
             err
-        })?;
+        })?
+    };
     debug!("Nostr event created and signed");
-    let client = state.nostr_client.lock().unwrap().clone().ok_or_else(|| {
-        let err = "No client".to_string();
-        error!("{}", err);
-        err
-    })?;
     client.send_event(event).await.map_err(|e| {
         let err = e.to_string();
         error!("Send event failed: {}", err);
         err
     })?;
-    info!("Message sent successfully to {}", recipient_nostr_pub);
-    Ok(Response { success: true, message: "Sent via Nostr".to_string(), data: None })
+    Ok(())
+}
+
+/// Sends `text` as a standard Nostr direct message, for contacts on other
+/// clients. These carry no `x_pub` tag, which is how we tell them apart.
+/// NIP-44 messages are sent as private direct messages (NIP-17), always gift
+/// wrapped: other clients do not read NIP-44 content in kind 4 events.
+async fn send_nostr_dm(client: &Client, identity: &Identity, recipient: nostr_sdk::PublicKey, format: Format, text: &str) -> Result<Response, String> {
+    let (builder, gift_wrap) = match format {
+        Format::Nip44 => (EventBuilder::private_msg_rumor(recipient, text, None), true),
+        Format::Nip04 => (EventBuilder::new(Kind::EncryptedDirectMessage, message::encrypt_nip04(&identity.nostr, &recipient, text)?, vec![Tag::public_key(recipient)]), false),
+        Format::Dumbchat => unreachable!("our own format is not a standard direct message"),
+    };
+    publish_dm(client, identity, recipient, builder, gift_wrap).await?;
+    info!("Message sent as {:?} to {}", format, recipient);
+    Ok(Response { success: true, message: format!("Sent via Nostr as {:?}", format), data: None })
+}
+
+/// Opens a gift wrap (NIP-59) addressed to us and returns the event sealed
+/// inside. The seal is signed by the sender, so the event is only accepted
+/// if it names the same author, and only if it is addressed to us: a rumor
+/// meant for someone else and rewrapped for us is refused.
+async fn unwrap_gift(keys: &Keys, gift_wrap: &Event) -> Result<UnsignedEvent, String> {
+    let unwrapped = UnwrappedGift::from_gift_wrap(keys, gift_wrap).await.map_err(|e| {
+        let err = format!("Gift wrap could not be opened: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    let mut rumor = unwrapped.rumor;
+    if rumor.pubkey != unwrapped.sender {
+        let err = format!("Gift wrapped event claims author {} but was sealed by {}", rumor.pubkey, unwrapped.sender);
+        error!("{}", err);
+        return Err(err);
+    }
+    if rumor.kind != Kind::EncryptedDirectMessage && rumor.kind != Kind::PrivateDirectMessage {
+        let err = format!("Gift wrapped event of unexpected kind {}", rumor.kind);
+        error!("{}", err);
+        return Err(err);
+    }
+    let our_pubkey = keys.public_key();
+    if !rumor.tags.public_keys().any(|pubkey| *pubkey == our_pubkey) {
+        let err = format!("Gift wrapped event from {} is not addressed to us", rumor.pubkey);
+        error!("{}", err);
+        return Err(err);
+    }
+    // The id is not signed, so it is computed rather than taken as sent.
+    rumor.id = None;
+    rumor.ensure_id();
+    Ok(rumor)
 }
 
 #[tauri::command]
@@ -464,25 +1867,9 @@ async fn receive_nostr_messages(
     window: tauri::Window,
 ) -> Result<Response, String> {
     info!("Starting to receive Nostr messages");
-    let login_data = match state.login.lock() {
-        Ok(guard) => guard.as_ref().ok_or_else(|| {
-            let err = "Not logged in".to_string();
-            error!("{}", err);
-            err
-        })?.clone(),
-        Err(e) => {
-            let err = format!("Failed to lock login state: {}", e);
-            error!("{}", err);
-            return Err(err);
-        }
-    };
-    debug!("Login data retrieved for receiving messages: username={}", login_data.username);
-    let nostr_keys = Keys::parse(&login_data.nostr_private).map_err(|e| {
-        let err = e.to_string();
-        error!("Keys parse failed: {}", err);
-        err
-    })?;
-    let our_pubkey = nostr_keys.public_key();
+    let session = current_session(&state).await?;
+    debug!("Session retrieved for receiving messages: username={}", session.username);
+    let our_pubkey = session.identity.nostr.public_key();
     debug!("Nostr public key retrieved: {:?}", our_pubkey);
     let client = match state.nostr_client.lock() {
         Ok(guard) => guard.clone().ok_or_else(|| {
@@ -501,8 +1888,12 @@ async fn receive_nostr_messages(
         .kind(Kind::EncryptedDirectMessage)
         .pubkey(our_pubkey)
         .limit(50);
-    debug!("Subscribing with filter: {:?}", filter);
-    if let Err(e) = client.subscribe(vec![filter], None).await {
+    let gift_wrap_filter = Filter::new()
+        .kind(Kind::GiftWrap)
+        .pubkey(our_pubkey)
+        .limit(50);
+    debug!("Subscribing with filters: {:?}, {:?}", filter, gift_wrap_filter);
+    if let Err(e) = client.subscribe(vec![filter, gift_wrap_filter], None).await {
         let err = format!("Subscribe failed: {}", e);
         error!("{}", err);
         return Err(err);
@@ -510,14 +1901,32 @@ async fn receive_nostr_messages(
     debug!("Subscribed to Nostr events");
     let window_clone = window.clone();
     let client_clone = client.clone();
-    let x_priv_hex_clone = login_data.x25519_private.clone();
-    spawn(async move {
+    let identity = session.identity.clone();
+    let task_session = session.clone();
+    let task = spawn(async move {
+        let mut announced = AnnouncedKeys::new();
         let mut notifications = client_clone.notifications();
         debug!("Started listening for notifications");
         while let Ok(notif) = notifications.recv().await {
             debug!("Received notification: {:?}", notif);
             if let RelayPoolNotification::Event { event, .. } = notif {
-                let ev = event.clone();
+                // A gift wrap is read as the event sealed inside it.
+                let gift_wrapped = event.kind == Kind::GiftWrap;
+                // A gift wrap's seal is checked by `unwrap_gift`; any other
+                // event is checked here, whatever the relay pool did.
+                let nostr_signed = gift_wrapped || event.verify().is_ok();
+                let ev = if gift_wrapped {
+                    match unwrap_gift(&identity.nostr, &event).await {
+                        Ok(rumor) => rumor,
+                        Err(e) => {
+                            error!("Dropping gift wrap: {}", e);
+                            continue;
+                        }
+                    }
+                } else {
+                    UnsignedEvent::from(*event)
+                };
+                let event_id = ev.id.map(|id| id.to_hex()).unwrap_or_default();
                 let sender_npub = ev.pubkey.to_bech32().unwrap_or_default();
                 let mut sender_x_pub_hex = None;
                 for tag in ev.tags.iter() {
@@ -531,75 +1940,166 @@ async fn receive_nostr_messages(
                 }
                 if let Some(sender_x_pub_hex) = sender_x_pub_hex {
                     debug!("Found x_pub tag: {}", sender_x_pub_hex);
-                    if let Ok(x_priv_bytes) = hex::decode(&x_priv_hex_clone) {
-                        if let Ok(x_priv_arr) = <[u8; 32]>::try_from(x_priv_bytes.as_slice()) {
-                            let our_secret = StaticSecret::from(x_priv_arr);
-                            if let Ok(sx_pub_bytes) = hex::decode(&sender_x_pub_hex) {
-                                if let Ok(sx_pub_arr) = <[u8; 32]>::try_from(sx_pub_bytes.as_slice()) {
-                                    let sender_pub = PublicKey::from(sx_pub_arr);
-                                    let shared_secret = our_secret.diffie_hellman(&sender_pub);
-                                    let key = Key::<Aes256Gcm>::from_slice(shared_secret.as_bytes());
-                                    let cipher = Aes256Gcm::new(key);
-                                    debug!("Shared secret and cipher created for decryption");
-                                    if let Ok(enc_payload) = serde_json::from_str::<EncryptedPayload>(&ev.content) {
-                                        let ct = match general_purpose::STANDARD.decode(&enc_payload.ciphertext) {
-                                            Ok(v) => v,
-                                            Err(e) => {
-                                                error!("Ciphertext decode failed: {}", e);
-                                                continue;
+                    if let Ok(sx_pub_bytes) = hex::decode(&sender_x_pub_hex) {
+                        if let Ok(sx_pub_arr) = <[u8; 32]>::try_from(sx_pub_bytes.as_slice()) {
+                            let sender_pub = PublicKey::from(sx_pub_arr);
+                            let verified = match check_sender_key(&client_clone, &task_session, &mut announced, ev.pubkey, &sender_npub, &sender_pub).await {
+                                SenderKey::Verified => true,
+                                SenderKey::Unannounced => {
+                                    debug!("No announced key for {}, marking message unverified", sender_npub);
+                                    false
+                                }
+                                SenderKey::Mismatch => {
+                                    error!("Dropping message from {}: x_pub does not match the sender's key", sender_npub);
+                                    let _ = window_clone.emit("message_rejected", json!({
+                                        "sender_npub": sender_npub,
+                                        "reason": "The message's encryption key does not belong to its sender",
+                                        "timestamp": ev.created_at.as_u64() as i64
+                                    }));
+                                    continue;
+                                }
+                            };
+                            if let Ok(enc_payload) = serde_json::from_str::<EncryptedPayload>(&ev.content) {
+                                let created_at = ev.created_at.as_u64();
+                                // Only a sender whose key is known or
+                                // announced may start or advance a session.
+                                if !verified && enc_payload.v >= message::RATCHET_VERSION {
+                                    error!("Dropping message from {}: no announced key to start a session with", sender_npub);
+                                    let _ = window_clone.emit("message_rejected", json!({
+                                        "sender_npub": sender_npub,
+                                        "reason": "The sender has not announced their encryption key",
+                                        "timestamp": created_at as i64
+                                    }));
+                                    continue;
+                                }
+                                let trust = task_session.lock_contacts().and_then(|mut contacts| contacts.observe(&sender_npub, &sender_pub));
+                                match trust {
+                                    Ok(Trust::Changed { verified }) => {
+                                        emit_key_changed(&window_clone, &sender_npub, verified);
+                                        if verified {
+                                            error!("Holding back message from {} until their new key is accepted", sender_npub);
+                                            continue;
+                                        }
+                                    }
+                                    Ok(_) => {}
+                                    Err(e) => error!("Failed to record key of {}: {}", sender_npub, e),
+                                }
+                                let decrypted = if enc_payload.v >= message::RATCHET_VERSION {
+                                    task_session.lock_ratchets().and_then(|mut ratchets| {
+                                        message::decrypt_ratchet(&mut ratchets, &identity.x25519, &sender_pub, &sender_npub, &enc_payload, &event_id)
+                                    })
+                                } else {
+                                    // Legacy payloads are let in by our clock,
+                                    // or if they were already read then.
+                                    let legacy = enc_payload.v == message::LEGACY_VERSION;
+                                    let read_before = legacy && task_session.lock_contacts().is_ok_and(|contacts| contacts.read_legacy(&sender_npub, &event_id));
+                                    let accept_legacy = read_before || message::legacy_window_open(attempts::now());
+                                    let decrypted = message::decrypt(&identity.x25519, &sender_pub, &enc_payload, accept_legacy);
+                                    if legacy && !read_before && decrypted.is_ok() {
+                                        if let Err(e) = task_session.lock_contacts().and_then(|mut contacts| contacts.remember_legacy(&sender_npub, &event_id)) {
+                                            error!("Failed to remember legacy message from {}: {}", sender_npub, e);
+                                        }
+                                    }
+                                    decrypted.map(Some)
+                                };
+                                // A session start may have used up one of our
+                                // one-time prekeys; publish a bundle without it.
+                                let started = enc_payload.header.as_ref().is_some_and(|header| header.x3dh.is_some());
+                                if started && matches!(decrypted, Ok(Some(_))) {
+                                    if let Err(e) = publish_prekeys(&client_clone, &task_session).await {
+                                        error!("Prekey republishing failed: {}", e);
+                                    }
+                                }
+                                if let Ok(Some(pt)) = decrypted {
+                                    if let Ok(content) = serde_json::from_slice::<Content>(&pt) {
+                                        // The signature must be valid, for us,
+                                        // and by the key the sender announced
+                                        // or first signed with.
+                                        let signer = content.verify(&identity.nostr.public_key().to_hex()).and_then(|signer| match signer {
+                                            Some(signing_key) => {
+                                                let announced_key = announced.get(&ev.pubkey).and_then(|(_, _, key)| key.as_ref());
+                                                let trusted = task_session.lock_contacts()?.observe_signer(&sender_npub, &signing_key, announced_key)?;
+                                                if trusted { Ok(true) } else { Err("The message was signed with a key that does not belong to its sender".to_string()) }
                                             }
-                                        };
-                                        let n_bytes = match general_purpose::STANDARD.decode(&enc_payload.nonce) {
-                                            Ok(v) => v,
-                                            Err(e) => {
-                                                error!("Nonce decode failed: {}", e);
+                                            None => Ok(false),
+                                        });
+                                        let signed = match signer {
+                                            Ok(signed) => signed,
+                                            Err(reason) => {
+                                                error!("Dropping message from {}: {}", sender_npub, reason);
+                                                let _ = window_clone.emit("message_rejected", json!({
+                                                    "sender_npub": sender_npub,
+                                                    "reason": reason,
+                                                    "timestamp": created_at as i64
+                                                }));
                                                 continue;
                                             }
                                         };
-                                        if n_bytes.len() != 12 {
-                                            error!("Invalid nonce length: {}", n_bytes.len());
-                                            continue;
-                                        }
-                                        let nonce = Nonce::from_slice(&n_bytes);
-                                        if let Ok(pt) = cipher.decrypt(nonce, ct.as_slice()) {
-                                            if let Ok(payload) = serde_json::from_slice::<serde_json::Value>(&pt) {
-                                                if let Some(text) = payload["text"].as_str() {
-                                                    debug!("Emitting new_message: sender_npub={}, timestamp={}", sender_npub, ev.created_at.as_u64());
-                                                    let _ = window_clone.emit("new_message", json!({
-                                                        "sender_npub": sender_npub,
-                                                        "text": text,
-                                                        "timestamp": ev.created_at.as_u64() as i64
-                                                    }));
-                                                } else {
-                                                    error!("Payload text field missing");
-                                                }
-                                            } else {
-                                                error!("Payload deserialization failed");
-                                            }
-                                        } else {
-                                            error!("Decryption failed");
-                                        }
+                                        // A signed timestamp cannot be changed
+                                        // by whoever published the event.
+                                        let timestamp = if signed { content.ts } else { created_at };
+                                        debug!("Emitting new_message: sender_npub={}, timestamp={}", sender_npub, timestamp);
+                                        let _ = window_clone.emit("new_message", json!({
+                                            "sender_npub": sender_npub,
+                                            "text": content.text,
+                                            "timestamp": timestamp as i64,
+                                            "legacy": enc_payload.v == message::LEGACY_VERSION,
+                                            "verified": verified,
+                                            "signature": if signed { "verified" } else { "unsigned" },
+                                            "format": Format::Dumbchat,
+                                            "gift_wrapped": gift_wrapped
+                                        }));
                                     } else {
-                                        error!("Encrypted payload parse failed");
+                                        error!("Payload deserialization failed");
                                     }
-                                } else {
-                                    error!("Sender x_pub decode failed");
                                 }
                             } else {
-                                error!("Sender x_pub hex decode failed");
+                                error!("Encrypted payload parse failed");
                             }
                         } else {
-                            error!("Private key decode failed");
+                            error!("Sender x_pub decode failed");
                         }
                     } else {
-                        error!("Private key hex decode failed");
+                        error!("Sender x_pub hex decode failed");
                     }
                 } else {
-                    error!("No x_pub tag found");
+                    // Without an x_pub tag, the message is from another
+                    // Nostr client, and only its Nostr signature ties it to
+                    // the author. NIP-44 authenticates the ciphertext under
+                    // the author's key as well; NIP-04 does not, so it is
+                    // never shown as verified and is flagged for the UI.
+                    // NIP-17 messages are only encrypted by their gift wrap.
+                    let decrypted = if ev.kind == Kind::PrivateDirectMessage {
+                        Ok((Format::Nip44, ev.content.clone()))
+                    } else {
+                        message::decrypt_nostr(&identity.nostr, &ev.pubkey, &ev.content)
+                    };
+                    match decrypted {
+                        Ok((format, text)) => {
+                            let verified = nostr_signed && format == Format::Nip44;
+                            debug!("Emitting new_message: sender_npub={}, format={:?}, verified={}", sender_npub, format, verified);
+                            let _ = window_clone.emit("new_message", json!({
+                                "sender_npub": sender_npub,
+                                "text": text,
+                                "timestamp": ev.created_at.as_u64() as i64,
+                                "legacy": false,
+                                "legacy_nip04": format == Format::Nip04,
+                                "verified": verified,
+                                "signature": if nostr_signed { "nostr" } else { "unsigned" },
+                                "format": format,
+                                "gift_wrapped": gift_wrapped
+                            }));
+                        }
+                        Err(e) => error!("Dropping message from {} without x_pub tag: {}", sender_npub, e),
+                    }
                 }
             }
         }
     });
+    if let Some(previous) = state.receive_task.lock().unwrap().replace(task) {
+        previous.abort();
+        debug!("Replaced previous receive task");
+    }
     info!("Started listening for Nostr messages");
     Ok(Response {
         success: true,
@@ -616,15 +2116,65 @@ fn main() {
     tauri::Builder::default()
         .manage(AppState::default())
         .plugin(tauri_plugin_fs::init())
+        .setup(|app| {
+            match get_accounts_dir(app.handle()) {
+                Ok(accounts_dir) => {
+                    if let Err(e) = accounts::migrate(&accounts_dir) {
+                        error!("Account migration failed: {}", e);
+                    }
+                }
+                Err(e) => error!("Account migration skipped: {}", e),
+            }
+            tauri::async_runtime::spawn(auto_lock_watchdog(app.handle().clone()));
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             create_account,
+            check_password_strength,
+            restore_account,
             login,
+            logout,
+            set_auto_lock,
+            set_recovery_key_threshold,
+            get_settings,
+            get_login_history,
+            change_password,
+            set_duress_password,
+            remove_duress_password,
+            split_recovery_key,
+            export_account,
+            import_account,
+            list_accounts,
+            rename_account,
+            delete_account,
             debug_login_state,
             get_user_info,
+            export_nostr_key,
+            list_contacts,
+            get_safety_number,
+            set_contact_verified,
+            accept_contact_key,
+            set_contact_format,
+            set_contact_gift_wrap,
             init_nostr_client,
             send_nostr_message,
             receive_nostr_messages
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unwrap_gift_checks_recipient() {
+        let (alice, bob, carol) = (Keys::generate(), Keys::generate(), Keys::generate());
+        let rumor = EventBuilder::private_msg_rumor(bob.public_key(), "hi", None).build(alice.public_key());
+        let wrapped = EventBuilder::gift_wrap(&alice, &bob.public_key(), rumor.clone(), None).await.unwrap();
+        assert_eq!(unwrap_gift(&bob, &wrapped).await.unwrap().content, "hi");
+        // Alice's rumor to Bob, sealed again for Carol.
+        let rewrapped = EventBuilder::gift_wrap(&alice, &carol.public_key(), rumor, None).await.unwrap();
+        assert!(unwrap_gift(&carol, &rewrapped).await.is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Nonce, Key};
+use base64::{Engine as _, engine::general_purpose};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use nostr_sdk::nips::{nip04, nip44};
+use nostr_sdk::Keys;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{debug, error, warn};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::prekeys::PeerBundle;
+use crate::ratchet::{Header, RatchetStore};
+
+/// Payload version of messages encrypted with a double ratchet session,
+/// which is what is sent. See `ratchet`.
+pub const RATCHET_VERSION: u8 = 3;
+/// Payload version with a per-message key from the static keys, still
+/// accepted from older clients.
+pub const PAYLOAD_VERSION: u8 = 2;
+/// Version 1 used the raw X25519 output as the AES key. Payloads without a
+/// version field are of this kind.
+pub const LEGACY_VERSION: u8 = 1;
+/// Legacy payloads received after this time (2027-01-01 UTC) by our own
+/// clock are rejected, which ends the transition to version 2. An event's
+/// `created_at` is chosen by its sender, so it does not count.
+const LEGACY_ACCEPTED_UNTIL: u64 = 1_798_761_600;
+
+/// HKDF `info` prefix for message keys. Bumping it changes every key.
+const MESSAGE_KEY_LABEL: &[u8] = b"dumbchat/message-key/v2";
+/// Domain separation for content signatures.
+const SIGNATURE_LABEL: &[u8] = b"dumbchat/message-signature/v1";
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// How messages to a contact are encrypted.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Our own payload, see `EncryptedPayload`. Only DumbChat can read it.
+    #[default]
+    Dumbchat,
+    /// NIP-44 v2 under the Nostr keys, which other Nostr clients read. It has
+    /// no forward secrecy and the content is not signed on its own.
+    Nip44,
+    /// NIP-04, for contacts whose clients only read that. AES-CBC without
+    /// authentication, with the IV and message length visible. Only sent to
+    /// contacts the user opted in.
+    Nip04,
+}
+
+/// Content of an encrypted direct message event.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EncryptedPayload {
+    #[serde(default = "legacy_version")]
+    pub v: u8,
+    /// Random per-message HKDF salt, so no two messages share a key.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub salt: String,
+    pub ciphertext: String,
+    /// Absent in ratchet payloads, where the nonce comes with the message key.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub nonce: String,
+    /// Ratchet header, in ratchet payloads only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<Header>,
+}
+
+fn legacy_version() -> u8 {
+    LEGACY_VERSION
+}
+
+/// Decrypted content of a message.
+///
+/// The sender signs it with their ed25519 identity key, covering the text,
+/// the time it was written and the recipient, so the recipient can tell who
+/// wrote it independently of who published the event. The signature sits
+/// inside the encryption: relays never see it, but the recipient holds a
+/// proof of authorship they could show others.
+#[derive(Serialize, Deserialize)]
+pub struct Content {
+    pub text: String,
+    /// Unix time the sender wrote the message.
+    #[serde(default)]
+    pub ts: u64,
+    /// Recipient's Nostr public key, hex.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub to: String,
+    /// Sender's ed25519 verifying key, hex.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub vk: String,
+    /// Signature over `signed_bytes`, hex.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sig: String,
+}
+
+fn signed_bytes(text: &str, ts: u64, to: &str) -> Vec<u8> {
+    let mut out = SIGNATURE_LABEL.to_vec();
+    out.extend_from_slice(&ts.to_be_bytes());
+    out.extend_from_slice(&(to.len() as u64).to_be_bytes());
+    out.extend_from_slice(to.as_bytes());
+    out.extend_from_slice(text.as_bytes());
+    out
+}
+
+impl Content {
+    /// Content for recipient `to`, signed with `key` at time `ts`.
+    pub fn signed(key: &SigningKey, text: String, ts: u64, to: String) -> Self {
+        let sig = key.sign(&signed_bytes(&text, ts, &to));
+        Content {
+            text,
+            ts,
+            to,
+            vk: hex::encode(key.verifying_key().as_bytes()),
+            sig: hex::encode(sig.to_bytes()),
+        }
+    }
+
+    /// Checks the signature and that we, Nostr key `our_nostr` in hex, are
+    /// the recipient. Returns the signer's key, or `None` for content from
+    /// clients that do not sign. Whether the key belongs to the sender is up
+    /// to the caller.
+    pub fn verify(&self, our_nostr: &str) -> Result<Option<VerifyingKey>, String> {
+        if self.sig.is_empty() {
+            return Ok(None);
+        }
+        let reject = |reason: &str| {
+            error!("Message signature rejected: {}", reason);
+            reason.to_string()
+        };
+        let mut vk = [0u8; 32];
+        hex::decode_to_slice(&self.vk, &mut vk).map_err(|_| reject("The message's signing key is malformed"))?;
+        let vk = VerifyingKey::from_bytes(&vk).map_err(|_| reject("The message's signing key is malformed"))?;
+        let mut sig = [0u8; 64];
+        hex::decode_to_slice(&self.sig, &mut sig).map_err(|_| reject("The message's signature is malformed"))?;
+        vk.verify_strict(&signed_bytes(&self.text, self.ts, &self.to), &Signature::from_bytes(&sig))
+            .map_err(|_| reject("The message's signature is invalid"))?;
+        if self.to != our_nostr {
+            return Err(reject("The message was signed for another recipient"));
+        }
+        Ok(Some(vk))
+    }
+}
+
+/// Derives the key for one message from `sender` to `recipient`.
+///
+/// The X25519 output is only the input keying material: HKDF mixes in the
+/// per-message salt and binds the protocol label and both public keys, in
+/// sending order, so keys are separate per direction and per message.
+fn message_key(our_secret: &StaticSecret, their_public: &PublicKey, sender: &PublicKey, recipient: &PublicKey, salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, String> {
+    let shared_secret = our_secret.diffie_hellman(their_public);
+    let hk = Hkdf::<Sha256>::new(Some(salt), shared_secret.as_bytes());
+    let info = [MESSAGE_KEY_LABEL, sender.as_bytes(), recipient.as_bytes()].concat();
+    let mut key = Zeroizing::new([0u8; 32]);
+    hk.expand(&info, key.as_mut_slice()).map_err(|e| {
+        let err = format!("Key derivation failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    Ok(key)
+}
+
+fn decode(field: &str, value: &str) -> Result<Vec<u8>, String> {
+    general_purpose::STANDARD.decode(value).map_err(|e| {
+        let err = format!("{} decode failed: {}", field, e);
+        error!("{}", err);
+        err
+    })
+}
+
+/// Encrypts `plaintext` from us to `recipient`, Nostr key `npub`, with the
+/// conversation's ratchet session, starting one from `bundle` if needed.
+pub fn encrypt_ratchet(store: &mut RatchetStore, our_secret: &StaticSecret, recipient: &PublicKey, npub: &str, bundle: Option<&PeerBundle>, plaintext: &[u8]) -> Result<EncryptedPayload, String> {
+    let (header, ciphertext) = store.encrypt(our_secret, recipient, npub, bundle, plaintext)?;
+    Ok(EncryptedPayload {
+        v: RATCHET_VERSION,
+        salt: String::new(),
+        ciphertext: general_purpose::STANDARD.encode(&ciphertext),
+        nonce: String::new(),
+        header: Some(header),
+    })
+}
+
+/// Decrypts a ratchet payload that `sender`, Nostr key `npub`, sent to us in
+/// event `event_id`. Returns `None` for an event that was already decrypted,
+/// which relays send again after every restart.
+pub fn decrypt_ratchet(store: &mut RatchetStore, our_secret: &StaticSecret, sender: &PublicKey, npub: &str, payload: &EncryptedPayload, event_id: &str) -> Result<Option<Vec<u8>>, String> {
+    let header = payload.header.as_ref().ok_or_else(|| {
+        let err = "Ratchet payload without header".to_string();
+        error!("{}", err);
+        err
+    })?;
+    let ciphertext = decode("Ciphertext", &payload.ciphertext)?;
+    let plaintext = store.decrypt(our_secret, sender, npub, event_id, header, &ciphertext)?;
+    debug!("Decrypted v{} message payload", payload.v);
+    Ok(plaintext)
+}
+
+/// Whether new legacy payloads are still accepted at local time `now`.
+pub fn legacy_window_open(now: u64) -> bool {
+    now <= LEGACY_ACCEPTED_UNTIL
+}
+
+/// Decrypts a payload that `sender` sent to us. Legacy payloads are only
+/// accepted with `accept_legacy`: while the transition window is open, or
+/// for events already read while it was.
+pub fn decrypt(our_secret: &StaticSecret, sender: &PublicKey, payload: &EncryptedPayload, accept_legacy: bool) -> Result<Vec<u8>, String> {
+    let ciphertext = decode("Ciphertext", &payload.ciphertext)?;
+    let nonce = decode("Nonce", &payload.nonce)?;
+    if nonce.len() != NONCE_LEN {
+        let err = format!("Invalid nonce length: {}", nonce.len());
+        error!("{}", err);
+        return Err(err);
+    }
+    let key = match payload.v {
+        PAYLOAD_VERSION => {
+            let salt = decode("Salt", &payload.salt)?;
+            if salt.len() != SALT_LEN {
+                let err = format!("Invalid salt length: {}", salt.len());
+                error!("{}", err);
+                return Err(err);
+            }
+            message_key(our_secret, sender, sender, &PublicKey::from(our_secret), &salt)?
+        }
+        LEGACY_VERSION if accept_legacy => {
+            warn!("Accepting legacy message payload");
+            Zeroizing::new(*our_secret.diffie_hellman(sender).as_bytes())
+        }
+        LEGACY_VERSION => {
+            let err = "Legacy message payloads are no longer accepted".to_string();
+            error!("{}", err);
+            return Err(err);
+        }
+        version => {
+            let err = format!("Unsupported message payload version {}", version);
+            error!("{}", err);
+            return Err(err);
+        }
+    };
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_slice()));
+    let plaintext = cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice()).map_err(|_| {
+        let err = "Decryption failed".to_string();
+        error!("{}", err);
+        err
+    })?;
+    debug!("Decrypted v{} message payload", payload.v);
+    Ok(plaintext)
+}
+
+/// Encrypts `text` for `recipient` with NIP-04 under our Nostr key.
+pub fn encrypt_nip04(our_keys: &Keys, recipient: &nostr_sdk::PublicKey, text: &str) -> Result<String, String> {
+    nip04::encrypt(our_keys.secret_key(), recipient, text).map_err(|e| {
+        let err = format!("NIP-04 encryption failed: {}", e);
+        error!("{}", err);
+        err
+    })
+}
+
+/// Decrypts the content of a direct message from another Nostr client,
+/// detecting its format. Returns the format and the text.
+pub fn decrypt_nostr(our_keys: &Keys, sender: &nostr_sdk::PublicKey, content: &str) -> Result<(Format, String), String> {
+    // NIP-04 content is `<base64>?iv=<base64>`; NIP-44 is plain base64.
+    if content.contains("?iv=") {
+        let text = nip04::decrypt(our_keys.secret_key(), sender, content).map_err(|e| {
+            let err = format!("NIP-04 decryption failed: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        warn!("Decrypted NIP-04 message, which is unauthenticated");
+        return Ok((Format::Nip04, text));
+    }
+    let text = nip44::decrypt(our_keys.secret_key(), sender, content).map_err(|e| {
+        let err = format!("NIP-44 decryption failed: {}", e);
+        error!("{}", err);
+        err
+    })?;
+    debug!("Decrypted NIP-44 message");
+    Ok((Format::Nip44, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn sealed(v: u8, key: &[u8], salt: &[u8], plaintext: &[u8]) -> EncryptedPayload {
+        let nonce = [3u8; NONCE_LEN];
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), plaintext).unwrap();
+        serde_json::from_value(serde_json::json!({
+            "v": v,
+            "salt": general_purpose::STANDARD.encode(salt),
+            "ciphertext": general_purpose::STANDARD.encode(ciphertext),
+            "nonce": general_purpose::STANDARD.encode(nonce),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn message_keys_depend_on_direction_and_salt() {
+        let (alice, bob) = (StaticSecret::random_from_rng(OsRng), StaticSecret::random_from_rng(OsRng));
+        let (alice_pub, bob_pub) = (PublicKey::from(&alice), PublicKey::from(&bob));
+        let salt = [1u8; SALT_LEN];
+        let to_bob = message_key(&alice, &bob_pub, &alice_pub, &bob_pub, &salt).unwrap();
+        let payload = sealed(PAYLOAD_VERSION, to_bob.as_slice(), &salt, b"hi bob");
+        assert_eq!(decrypt(&bob, &alice_pub, &payload, false).unwrap(), b"hi bob");
+        let to_alice = message_key(&bob, &alice_pub, &bob_pub, &alice_pub, &salt).unwrap();
+        assert_ne!(*to_bob, *to_alice);
+        let other_salt = message_key(&alice, &bob_pub, &alice_pub, &bob_pub, &[2u8; SALT_LEN]).unwrap();
+        assert_ne!(*to_bob, *other_salt);
+        // A message keyed for the other direction does not open.
+        let reflected = sealed(PAYLOAD_VERSION, to_alice.as_slice(), &salt, b"hi bob");
+        assert!(decrypt(&bob, &alice_pub, &reflected, false).is_err());
+    }
+
+    #[test]
+    fn legacy_payloads_close_with_the_window() {
+        let (alice, bob) = (StaticSecret::random_from_rng(OsRng), StaticSecret::random_from_rng(OsRng));
+        let alice_pub = PublicKey::from(&alice);
+        let shared = alice.diffie_hellman(&PublicKey::from(&bob));
+        let payload = sealed(LEGACY_VERSION, shared.as_bytes(), &[], b"old");
+        assert_eq!(decrypt(&bob, &alice_pub, &payload, true).unwrap(), b"old");
+        assert!(decrypt(&bob, &alice_pub, &payload, false).is_err());
+        assert!(legacy_window_open(LEGACY_ACCEPTED_UNTIL));
+        assert!(!legacy_window_open(LEGACY_ACCEPTED_UNTIL + 1));
+    }
+
+    #[test]
+    fn nip44_messages_roundtrip() {
+        let (alice, bob) = (Keys::generate(), Keys::generate());
+        let content = nip44::encrypt(alice.secret_key(), &bob.public_key(), "hello nostr", nip44::Version::V2).unwrap();
+        let (format, text) = decrypt_nostr(&bob, &alice.public_key(), &content).unwrap();
+        assert_eq!((format, text.as_str()), (Format::Nip44, "hello nostr"));
+    }
+}
@@ -0,0 +1,71 @@
+use serde::Serialize;
+use tracing::{debug, error};
+use zxcvbn::{zxcvbn, Score};
+
+/// Lowest accepted zxcvbn score, about 10^10 guesses. Combined with the
+/// Argon2 cost per guess, that keeps an offline attack on a stolen keystore
+/// out of reach.
+const MIN_SCORE: Score = Score::Three;
+/// zxcvbn gets slow on very long input, and anything past this many
+/// characters adds nothing to the verdict.
+const MAX_SCORED_CHARS: usize = 100;
+
+/// Result of scoring a password, shown to the user while they type.
+#[derive(Serialize, Debug)]
+pub struct Strength {
+    /// zxcvbn score from 0 to 4.
+    pub score: u8,
+    /// Estimated entropy in bits, from zxcvbn's guess count.
+    pub entropy_bits: f64,
+    pub acceptable: bool,
+    /// Why the password is weak and how to improve it. Empty when acceptable.
+    pub feedback: String,
+}
+
+/// Scores `password`, treating the words in `context` (such as the username)
+/// as known to an attacker.
+pub fn strength(password: &str, context: &[&str]) -> Strength {
+    let scored: String = password.chars().take(MAX_SCORED_CHARS).collect();
+    let estimate = zxcvbn(&scored, context);
+    let acceptable = estimate.score() >= MIN_SCORE;
+    let feedback = match (acceptable, estimate.feedback()) {
+        (true, _) => String::new(),
+        (false, Some(feedback)) if !feedback.to_string().trim().is_empty() => feedback.to_string().trim().to_string(),
+        (false, _) => "It would be too easy to guess. Add another word or two.".to_string(),
+    };
+    Strength {
+        score: u8::from(estimate.score()),
+        entropy_bits: (estimate.guesses_log10() * std::f64::consts::LOG2_10).max(0.0),
+        acceptable,
+        feedback,
+    }
+}
+
+/// Rejects a new password that scores below `MIN_SCORE`, giving the reason.
+pub fn check(password: &str, context: &[&str]) -> Result<(), String> {
+    let strength = strength(password, context);
+    debug!("Password scored {} ({:.0} bits)", strength.score, strength.entropy_bits);
+    if !strength.acceptable {
+        error!("Password rejected with score {}", strength.score);
+        return Err(format!("Password is too weak: {}", strength.feedback));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weak_passwords_are_rejected_with_feedback() {
+        assert!(check("password1", &[]).is_err());
+        // The username is known to an attacker and adds nothing.
+        assert!(check("alice-alice-alice", &["alice"]).is_err());
+        let weak = strength("qwerty", &[]);
+        assert!(!weak.acceptable);
+        assert!(!weak.feedback.is_empty());
+        assert!(check("correct horse battery staple", &["alice"]).is_ok());
+        // Long input is cut short rather than scored in full.
+        assert!(!strength(&"a".repeat(10_000), &[]).acceptable);
+    }
+}
@@ -0,0 +1,293 @@
+use aes_gcm::aead::OsRng;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::{debug, error, info};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+use crate::attempts::now;
+use crate::storage::{self, SealedFile};
+
+/// Version of the published bundle content.
+pub const BUNDLE_VERSION: u8 = 1;
+/// `d` tag of the bundle event, which is addressable (NIP-01), so each
+/// publication replaces the previous one.
+pub const BUNDLE_IDENTIFIER: &str = "dumbchat/prekeys";
+/// Prefix of what the signed prekey's signature covers.
+const SIGNED_PREKEY_LABEL: &[u8] = b"dumbchat/prekeys/spk";
+
+/// A new signed prekey is made this often.
+const SIGNED_PREKEY_ROTATION: u64 = 7 * 24 * 60 * 60;
+/// Replaced signed prekeys are kept this long after rotation, for session
+/// starts that were sent before the new bundle reached the sender.
+const SIGNED_PREKEY_GRACE: u64 = 30 * 24 * 60 * 60;
+/// One-time prekeys are topped up to this many once they fall below
+/// `ONE_TIME_PREKEY_LOW`.
+const ONE_TIME_PREKEY_TARGET: usize = 50;
+const ONE_TIME_PREKEY_LOW: usize = 20;
+
+/// Content of the bundle event. The event is signed with the account's
+/// Nostr key, which signs the identity key and signed prekey along with it,
+/// so the bundle also announces which X25519 and ed25519 keys belong to the
+/// account. The signed prekey is also signed with the ed25519 key, which
+/// contacts pin, so a bundle published under a leaked Nostr key cannot swap
+/// it without also changing the pinned key. That signature covers the Nostr
+/// key too, so the bundle cannot be republished by another account.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Bundle {
+    pub v: u8,
+    /// X25519 identity key, hex.
+    pub ik: String,
+    /// Signed prekey, hex.
+    pub spk: String,
+    /// One-time prekeys, hex.
+    #[serde(default)]
+    pub opks: Vec<String>,
+    /// ed25519 key that signs the account's messages, hex.
+    pub ed: String,
+    /// Signature of `ed` over the Nostr key, identity key and signed
+    /// prekey, hex.
+    pub sig: String,
+}
+
+/// A fetched bundle with its keys decoded.
+pub struct PeerBundle {
+    pub identity: PublicKey,
+    pub signed_prekey: PublicKey,
+    pub one_time_prekey: Option<PublicKey>,
+    pub verifying_key: VerifyingKey,
+}
+
+/// What the signature on a signed prekey published by Nostr key `author`,
+/// hex, covers.
+fn signed_prekey_bytes(author: &str, identity: &PublicKey, signed_prekey: &PublicKey) -> Vec<u8> {
+    let mut out = SIGNED_PREKEY_LABEL.to_vec();
+    out.extend_from_slice(&(author.len() as u64).to_be_bytes());
+    out.extend_from_slice(author.as_bytes());
+    out.extend_from_slice(identity.as_bytes());
+    out.extend_from_slice(signed_prekey.as_bytes());
+    out
+}
+
+fn decode_key(field: &str, value: &str) -> Result<PublicKey, String> {
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(value, &mut key).map_err(|e| {
+        let err = format!("Invalid {} in prekey bundle: {}", field, e);
+        error!("{}", err);
+        err
+    })?;
+    Ok(PublicKey::from(key))
+}
+
+impl Bundle {
+    /// Decodes the bundle published by Nostr key `author`, hex, and picks
+    /// one of its one-time prekeys at random.
+    pub fn parse(content: &str, author: &str) -> Result<PeerBundle, String> {
+        let bundle: Bundle = serde_json::from_str(content).map_err(|e| {
+            let err = format!("Invalid prekey bundle: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        if bundle.v != BUNDLE_VERSION {
+            let err = format!("Unsupported prekey bundle version {}", bundle.v);
+            error!("{}", err);
+            return Err(err);
+        }
+        let one_time_prekey = match bundle.opks.len() {
+            0 => None,
+            n => Some(decode_key("one-time prekey", &bundle.opks[rand::random::<usize>() % n])?),
+        };
+        let verifying_key = VerifyingKey::from_bytes(decode_key("signing key", &bundle.ed)?.as_bytes()).map_err(|e| {
+            let err = format!("Invalid signing key in prekey bundle: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        let identity = decode_key("identity key", &bundle.ik)?;
+        let signed_prekey = decode_key("signed prekey", &bundle.spk)?;
+        let mut sig = [0u8; 64];
+        hex::decode_to_slice(&bundle.sig, &mut sig).map_err(|e| {
+            let err = format!("Invalid signature in prekey bundle: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        verifying_key.verify_strict(&signed_prekey_bytes(author, &identity, &signed_prekey), &Signature::from_bytes(&sig)).map_err(|_| {
+            let err = "The signed prekey is not signed by the bundle's signing key".to_string();
+            error!("{}", err);
+            err
+        })?;
+        Ok(PeerBundle { identity, signed_prekey, one_time_prekey, verifying_key })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedPrekey {
+    secret: [u8; 32],
+    public: [u8; 32],
+    created_at: u64,
+}
+
+impl Drop for SignedPrekey {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Prekeys {
+    /// Newest last; the last one is the one published.
+    signed: Vec<SignedPrekey>,
+    /// Secrets of unused one-time prekeys by public key, hex.
+    one_time: BTreeMap<String, [u8; 32]>,
+}
+
+impl Drop for Prekeys {
+    fn drop(&mut self) {
+        self.one_time.values_mut().for_each(|secret| secret.zeroize());
+    }
+}
+
+/// Private halves of the prekeys an identity publishes.
+pub struct PrekeyStore {
+    file: SealedFile,
+    prekeys: Prekeys,
+    /// Bumped on every change to the published keys.
+    revision: u64,
+    /// Revision last published. Not persisted, so every run publishes once.
+    published: Option<u64>,
+}
+
+impl PrekeyStore {
+    pub fn open(data_dir: &Path, our_static: &StaticSecret) -> Self {
+        let file = SealedFile::new(data_dir, our_static, "prekeys", "prekeys");
+        // Without the old secrets, pending session starts fail and the peers
+        // fall back to a new bundle.
+        let prekeys = file.load().unwrap_or_else(|e| {
+            error!("Starting with new prekeys: {}", e);
+            None
+        }).unwrap_or_default();
+        PrekeyStore { file, prekeys, revision: 0, published: None }
+    }
+
+    /// Rotates the signed prekey when due, forgets expired ones and tops up
+    /// the one-time prekeys. Saves if anything changed.
+    pub fn maintain(&mut self) -> Result<(), String> {
+        let now = now();
+        let mut changed = false;
+        if self.prekeys.signed.last().is_none_or(|spk| now.saturating_sub(spk.created_at) >= SIGNED_PREKEY_ROTATION) {
+            let secret = StaticSecret::random_from_rng(OsRng);
+            self.prekeys.signed.push(SignedPrekey {
+                public: PublicKey::from(&secret).to_bytes(),
+                secret: secret.to_bytes(),
+                created_at: now,
+            });
+            info!("Rotated signed prekey");
+            changed = true;
+        }
+        // A replaced key expires a grace period after its successor was made.
+        let expired = self.prekeys.signed.windows(2)
+            .take_while(|pair| now.saturating_sub(pair[1].created_at) >= SIGNED_PREKEY_GRACE)
+            .count();
+        if expired > 0 {
+            self.prekeys.signed.drain(..expired);
+            debug!("Dropped {} expired signed prekeys", expired);
+            changed = true;
+        }
+        if self.prekeys.one_time.len() < ONE_TIME_PREKEY_LOW {
+            let added = ONE_TIME_PREKEY_TARGET - self.prekeys.one_time.len();
+            for _ in 0..added {
+                let secret = StaticSecret::random_from_rng(OsRng);
+                self.prekeys.one_time.insert(hex::encode(PublicKey::from(&secret).as_bytes()), secret.to_bytes());
+            }
+            info!("Added {} one-time prekeys", added);
+            changed = true;
+        }
+        if changed {
+            self.revision += 1;
+            self.file.store(&self.prekeys)?;
+        }
+        Ok(())
+    }
+
+    /// The bundle to publish and its revision, if it changed since it was
+    /// last published.
+    pub fn unpublished(&self, our_nostr: &str, our_static: &StaticSecret, our_signing: &SigningKey) -> Option<(u64, Bundle)> {
+        if self.published == Some(self.revision) {
+            return None;
+        }
+        let spk = self.prekeys.signed.last()?;
+        let identity = PublicKey::from(our_static);
+        let sig = our_signing.sign(&signed_prekey_bytes(our_nostr, &identity, &PublicKey::from(spk.public)));
+        let bundle = Bundle {
+            v: BUNDLE_VERSION,
+            ik: hex::encode(identity.as_bytes()),
+            spk: hex::encode(spk.public),
+            opks: self.prekeys.one_time.keys().cloned().collect(),
+            ed: hex::encode(our_signing.verifying_key().as_bytes()),
+            sig: hex::encode(sig.to_bytes()),
+        };
+        Some((self.revision, bundle))
+    }
+
+    pub fn mark_published(&mut self, revision: u64) {
+        self.published = Some(revision);
+    }
+
+    /// Secret of one of our signed prekeys, including replaced ones still
+    /// in their grace period.
+    pub fn signed_prekey(&self, public: &str) -> Option<StaticSecret> {
+        self.prekeys.signed.iter()
+            .find(|spk| hex::encode(spk.public) == public)
+            .map(|spk| StaticSecret::from(spk.secret))
+    }
+
+    pub fn one_time_prekey(&self, public: &str) -> Option<StaticSecret> {
+        self.prekeys.one_time.get(public).map(|secret| StaticSecret::from(*secret))
+    }
+
+    /// Forgets a one-time prekey once a session was started with it, so it
+    /// cannot be used again, and schedules a new bundle without it.
+    pub fn consume_one_time_prekey(&mut self, public: &str) -> Result<(), String> {
+        if let Some(mut secret) = self.prekeys.one_time.remove(public) {
+            secret.zeroize();
+            self.revision += 1;
+            self.file.store(&self.prekeys)?;
+            debug!("Consumed one-time prekey");
+        }
+        Ok(())
+    }
+}
+
+/// Shreds the prekey files of every identity in `data_dir` but `our_static`.
+pub fn shred_other_stores(data_dir: &Path, our_static: &StaticSecret) -> Result<(), String> {
+    storage::shred_others(data_dir, "prekeys", &SealedFile::new(data_dir, our_static, "prekeys", "prekeys"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_prekey_must_be_signed_by_the_announced_key() {
+        let dir = std::env::temp_dir().join(format!("dumbchat-prekeys-{}", hex::encode(rand::random::<[u8; 8]>())));
+        let ours = StaticSecret::random_from_rng(OsRng);
+        let signing = SigningKey::generate(&mut OsRng);
+        let mut store = PrekeyStore::open(&dir, &ours);
+        store.maintain().unwrap();
+        let (_, bundle) = store.unpublished("alice", &ours, &signing).unwrap();
+        let parsed = Bundle::parse(&serde_json::to_string(&bundle).unwrap(), "alice").unwrap();
+        assert_eq!(parsed.identity, PublicKey::from(&ours));
+        assert_eq!(parsed.verifying_key, signing.verifying_key());
+        // Another publisher, another signed prekey, or another key vouching
+        // for it, is refused.
+        assert!(Bundle::parse(&serde_json::to_string(&bundle).unwrap(), "mallory").is_err());
+        let mut swapped = serde_json::to_value(&bundle).unwrap();
+        swapped["spk"] = hex::encode(PublicKey::from(&StaticSecret::random_from_rng(OsRng)).as_bytes()).into();
+        assert!(Bundle::parse(&swapped.to_string(), "alice").is_err());
+        let mut resigned = serde_json::to_value(&bundle).unwrap();
+        resigned["ed"] = hex::encode(SigningKey::generate(&mut OsRng).verifying_key().as_bytes()).into();
+        assert!(Bundle::parse(&resigned.to_string(), "alice").is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,730 @@
+use aes_gcm::{aead::{Aead, KeyInit, OsRng, Payload}, Aes256Gcm, Nonce, Key};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+use tracing::{debug, error, info, warn};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::prekeys::{self, PeerBundle, PrekeyStore};
+use crate::storage::{self, SealedFile};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// HKDF labels. Bumping any of them breaks every existing session.
+const INIT_LABEL: &[u8] = b"dumbchat/ratchet/init";
+const X3DH_LABEL: &[u8] = b"dumbchat/x3dh";
+const ROOT_LABEL: &[u8] = b"dumbchat/ratchet/root";
+const MESSAGE_LABEL: &[u8] = b"dumbchat/ratchet/message";
+
+/// Most message keys skipped in one chain for a single incoming message.
+/// Anything further ahead is treated as garbage rather than derived.
+const MAX_SKIP: u32 = 1000;
+/// Most skipped message keys kept per conversation; the oldest go first.
+const MAX_STORED_SKIPPED: usize = 2000;
+/// Event ids remembered per conversation, so relays replaying old events
+/// after a restart are ignored instead of failing or resetting the session.
+const MAX_SEEN_EVENTS: usize = 500;
+/// Session starts remembered per conversation.
+const MAX_STARTED: usize = 100;
+
+/// Sent in the clear with every ratchet message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Header {
+    /// Sender's current ratchet public key, hex.
+    pub dh: String,
+    /// Length of the sender's previous sending chain.
+    pub pn: u32,
+    /// Number of this message in the current sending chain.
+    pub n: u32,
+    /// Set until the sender has heard back in this session, so the
+    /// recipient can start a session from it.
+    #[serde(default)]
+    pub init: bool,
+    /// For sessions started from the recipient's prekey bundle, the keys
+    /// the recipient needs to derive the same secret. Sent with `init`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x3dh: Option<Handshake>,
+}
+
+/// X3DH keys of a session start, hex.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Handshake {
+    /// Sender's ephemeral key.
+    pub ek: String,
+    /// Recipient's signed prekey that was used.
+    pub spk: String,
+    /// Recipient's one-time prekey that was used, if the bundle had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opk: Option<String>,
+}
+
+fn decode_key(field: &str, value: &str) -> Result<[u8; KEY_LEN], String> {
+    let mut key = [0u8; KEY_LEN];
+    hex::decode_to_slice(value, &mut key).map_err(|e| {
+        let err = format!("Invalid {} in header: {}", field, e);
+        error!("{}", err);
+        err
+    })?;
+    Ok(key)
+}
+
+impl Header {
+    fn dh_bytes(&self) -> Result<[u8; KEY_LEN], String> {
+        decode_key("ratchet key", &self.dh)
+    }
+
+    /// Associated data of the message: the header followed by `ad`.
+    fn aad(&self, ad: &[u8]) -> Result<Vec<u8>, String> {
+        let mut out = self.dh_bytes()?.to_vec();
+        out.extend_from_slice(&self.pn.to_be_bytes());
+        out.extend_from_slice(&self.n.to_be_bytes());
+        out.push(self.init as u8);
+        if let Some(handshake) = &self.x3dh {
+            out.extend_from_slice(&decode_key("ephemeral key", &handshake.ek)?);
+            out.extend_from_slice(&decode_key("signed prekey", &handshake.spk)?);
+            if let Some(opk) = &handshake.opk {
+                out.extend_from_slice(&decode_key("one-time prekey", opk)?);
+            }
+        }
+        out.extend_from_slice(ad);
+        Ok(out)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SkippedKey {
+    dh: [u8; KEY_LEN],
+    n: u32,
+    mk: [u8; KEY_LEN],
+}
+
+impl Drop for SkippedKey {
+    fn drop(&mut self) {
+        self.mk.zeroize();
+    }
+}
+
+/// Double ratchet state of one conversation, as in Signal's specification.
+///
+/// Every message gets a fresh key from a symmetric chain, and every change of
+/// speaker mixes a new X25519 exchange into the root key, so a leaked static
+/// key or state does not expose messages from before the last turn.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Ratchet {
+    dhs_secret: [u8; KEY_LEN],
+    dhs_public: [u8; KEY_LEN],
+    dhr: Option<[u8; KEY_LEN]>,
+    rk: [u8; KEY_LEN],
+    cks: Option<[u8; KEY_LEN]>,
+    ckr: Option<[u8; KEY_LEN]>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    /// Whether anything has been received in this session.
+    confirmed: bool,
+    /// Sent along until the session is confirmed.
+    #[serde(default)]
+    handshake: Option<Handshake>,
+    skipped: VecDeque<SkippedKey>,
+}
+
+impl Drop for Ratchet {
+    fn drop(&mut self) {
+        self.dhs_secret.zeroize();
+        self.rk.zeroize();
+        self.cks.zeroize();
+        self.ckr.zeroize();
+    }
+}
+
+fn dh(secret: &[u8; KEY_LEN], public: &[u8; KEY_LEN]) -> Zeroizing<[u8; KEY_LEN]> {
+    let secret = StaticSecret::from(*secret);
+    Zeroizing::new(*secret.diffie_hellman(&PublicKey::from(*public)).as_bytes())
+}
+
+fn generate_dh() -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    (secret.to_bytes(), PublicKey::from(&secret).to_bytes())
+}
+
+fn hkdf(salt: Option<&[u8]>, ikm: &[u8], info: &[u8], okm: &mut [u8]) -> Result<(), String> {
+    Hkdf::<Sha256>::new(salt, ikm).expand(info, okm).map_err(|e| {
+        let err = format!("Key derivation failed: {}", e);
+        error!("{}", err);
+        err
+    })
+}
+
+/// Root chain step: a new root key and a new chain key.
+fn kdf_rk(rk: &[u8; KEY_LEN], dh_out: &[u8; KEY_LEN]) -> Result<([u8; KEY_LEN], [u8; KEY_LEN]), String> {
+    let mut okm = Zeroizing::new([0u8; 2 * KEY_LEN]);
+    hkdf(Some(rk), dh_out, ROOT_LABEL, okm.as_mut_slice())?;
+    let mut root = [0u8; KEY_LEN];
+    let mut chain = [0u8; KEY_LEN];
+    root.copy_from_slice(&okm[..KEY_LEN]);
+    chain.copy_from_slice(&okm[KEY_LEN..]);
+    Ok((root, chain))
+}
+
+/// Symmetric chain step: the next chain key and this message's key.
+fn kdf_ck(ck: &[u8; KEY_LEN]) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+    let step = |byte: u8| {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(ck).expect("HMAC accepts any key length");
+        mac.update(&[byte]);
+        let mut out = [0u8; KEY_LEN];
+        out.copy_from_slice(&mac.finalize().into_bytes());
+        out
+    };
+    (step(2), step(1))
+}
+
+/// AES key and nonce for one message key. Each message key is used once,
+/// so the nonce can be derived rather than sent.
+fn message_cipher(mk: &[u8; KEY_LEN]) -> Result<(Aes256Gcm, [u8; NONCE_LEN]), String> {
+    let mut okm = Zeroizing::new([0u8; KEY_LEN + NONCE_LEN]);
+    hkdf(None, mk, MESSAGE_LABEL, okm.as_mut_slice())?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&okm[..KEY_LEN]));
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(&okm[KEY_LEN..]);
+    Ok((cipher, nonce))
+}
+
+/// Secret both sides derive from their static keys to start a session.
+fn initial_secret(shared: &[u8; KEY_LEN], initiator: &PublicKey, responder: &PublicKey) -> Result<[u8; KEY_LEN], String> {
+    let info = [INIT_LABEL, initiator.as_bytes(), responder.as_bytes()].concat();
+    let mut sk = [0u8; KEY_LEN];
+    hkdf(None, shared, &info, &mut sk)?;
+    Ok(sk)
+}
+
+/// X3DH secret from the four exchanges between the initiator's identity
+/// and ephemeral keys and the responder's identity and prekeys. The pairs
+/// are given from the initiator's side as (ours, theirs) in the same order.
+fn x3dh_secret(exchanges: &[Zeroizing<[u8; KEY_LEN]>], initiator: &PublicKey, responder: &PublicKey) -> Result<[u8; KEY_LEN], String> {
+    let mut ikm = Zeroizing::new(Vec::with_capacity(exchanges.len() * KEY_LEN));
+    exchanges.iter().for_each(|exchange| ikm.extend_from_slice(exchange.as_slice()));
+    let info = [X3DH_LABEL, initiator.as_bytes(), responder.as_bytes()].concat();
+    let mut sk = [0u8; KEY_LEN];
+    hkdf(None, &ikm, &info, &mut sk)?;
+    Ok(sk)
+}
+
+impl Ratchet {
+    /// Starts a session towards `their_static`, to send first.
+    pub fn initiate(our_static: &StaticSecret, their_static: &PublicKey) -> Result<Self, String> {
+        let shared = Zeroizing::new(*our_static.diffie_hellman(their_static).as_bytes());
+        let sk = initial_secret(&shared, &PublicKey::from(our_static), their_static)?;
+        Self::start(sk, their_static)
+    }
+
+    /// Starts a session from the peer's prekey bundle, with X3DH. The first
+    /// messages carry the ephemeral key and prekeys used, until the peer
+    /// answers.
+    pub fn initiate_x3dh(our_static: &StaticSecret, bundle: &PeerBundle) -> Result<Self, String> {
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let mut exchanges = vec![
+            Zeroizing::new(*our_static.diffie_hellman(&bundle.signed_prekey).as_bytes()),
+            Zeroizing::new(*ephemeral.diffie_hellman(&bundle.identity).as_bytes()),
+            Zeroizing::new(*ephemeral.diffie_hellman(&bundle.signed_prekey).as_bytes()),
+        ];
+        if let Some(opk) = &bundle.one_time_prekey {
+            exchanges.push(Zeroizing::new(*ephemeral.diffie_hellman(opk).as_bytes()));
+        }
+        let sk = x3dh_secret(&exchanges, &PublicKey::from(our_static), &bundle.identity)?;
+        let mut ratchet = Self::start(sk, &bundle.signed_prekey)?;
+        ratchet.handshake = Some(Handshake {
+            ek: hex::encode(PublicKey::from(&ephemeral).as_bytes()),
+            spk: hex::encode(bundle.signed_prekey.as_bytes()),
+            opk: bundle.one_time_prekey.map(|opk| hex::encode(opk.as_bytes())),
+        });
+        Ok(ratchet)
+    }
+
+    /// Sending side of a new session from shared secret `sk`, with the
+    /// peer's first ratchet key `their_ratchet`.
+    fn start(mut sk: [u8; KEY_LEN], their_ratchet: &PublicKey) -> Result<Self, String> {
+        let (dhs_secret, dhs_public) = generate_dh();
+        let started = kdf_rk(&sk, &dh(&dhs_secret, their_ratchet.as_bytes()));
+        sk.zeroize();
+        let (rk, cks) = started?;
+        Ok(Ratchet {
+            dhs_secret,
+            dhs_public,
+            dhr: Some(their_ratchet.to_bytes()),
+            rk,
+            cks: Some(cks),
+            ckr: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            confirmed: false,
+            handshake: None,
+            skipped: VecDeque::new(),
+        })
+    }
+
+    /// Starts a session from the first message of `their_static`, whose
+    /// first ratchet key was combined with our static key.
+    pub fn respond(our_static: &StaticSecret, their_static: &PublicKey) -> Result<Self, String> {
+        let shared = Zeroizing::new(*our_static.diffie_hellman(their_static).as_bytes());
+        let rk = initial_secret(&shared, their_static, &PublicKey::from(our_static))?;
+        Ok(Self::answer(rk, our_static))
+    }
+
+    /// Starts a session from the first message of `their_static` that used
+    /// our prekeys. `signed_prekey` becomes our first ratchet key.
+    pub fn respond_x3dh(our_static: &StaticSecret, their_static: &PublicKey, signed_prekey: &StaticSecret, one_time_prekey: Option<&StaticSecret>, handshake: &Handshake) -> Result<Self, String> {
+        let ephemeral = PublicKey::from(decode_key("ephemeral key", &handshake.ek)?);
+        let mut exchanges = vec![
+            Zeroizing::new(*signed_prekey.diffie_hellman(their_static).as_bytes()),
+            Zeroizing::new(*our_static.diffie_hellman(&ephemeral).as_bytes()),
+            Zeroizing::new(*signed_prekey.diffie_hellman(&ephemeral).as_bytes()),
+        ];
+        if let Some(opk) = one_time_prekey {
+            exchanges.push(Zeroizing::new(*opk.diffie_hellman(&ephemeral).as_bytes()));
+        }
+        let rk = x3dh_secret(&exchanges, their_static, &PublicKey::from(our_static))?;
+        Ok(Self::answer(rk, signed_prekey))
+    }
+
+    /// Receiving side of a new session from root key `rk`, waiting for the
+    /// peer's first ratchet key.
+    fn answer(rk: [u8; KEY_LEN], our_ratchet: &StaticSecret) -> Self {
+        Ratchet {
+            dhs_secret: our_ratchet.to_bytes(),
+            dhs_public: PublicKey::from(our_ratchet).to_bytes(),
+            dhr: None,
+            rk,
+            cks: None,
+            ckr: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            confirmed: false,
+            handshake: None,
+            skipped: VecDeque::new(),
+        }
+    }
+
+    /// Encrypts the next message. `ad` is bound to the ciphertext along with
+    /// the header.
+    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<(Header, Vec<u8>), String> {
+        let cks = self.cks.ok_or_else(|| {
+            let err = "Ratchet has no sending chain yet".to_string();
+            error!("{}", err);
+            err
+        })?;
+        let (next, mut mk) = kdf_ck(&cks);
+        let header = Header { dh: hex::encode(self.dhs_public), pn: self.pn, n: self.ns, init: !self.confirmed, x3dh: self.handshake.clone() };
+        let (cipher, nonce) = message_cipher(&mk)?;
+        mk.zeroize();
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &header.aad(ad)? }).map_err(|e| {
+            let err = format!("Encryption failed: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        self.cks = Some(next);
+        self.ns += 1;
+        Ok((header, ciphertext))
+    }
+
+    /// Decrypts a message, handling skipped and out-of-order ones. The state
+    /// only changes if the message authenticates.
+    pub fn decrypt(&mut self, header: &Header, ciphertext: &[u8], ad: &[u8]) -> Result<Vec<u8>, String> {
+        let mut next = self.clone();
+        let plaintext = next.decrypt_in_place(header, ciphertext, ad)?;
+        next.confirmed = true;
+        next.handshake = None;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn decrypt_in_place(&mut self, header: &Header, ciphertext: &[u8], ad: &[u8]) -> Result<Vec<u8>, String> {
+        let dh_pub = header.dh_bytes()?;
+        let aad = header.aad(ad)?;
+        if let Some(position) = self.skipped.iter().position(|key| key.dh == dh_pub && key.n == header.n) {
+            let key = self.skipped.remove(position).unwrap();
+            debug!("Using skipped message key {}", header.n);
+            return Self::open(&key.mk, ciphertext, &aad);
+        }
+        if self.dhr != Some(dh_pub) {
+            self.skip_message_keys(header.pn)?;
+            self.dh_ratchet(&dh_pub)?;
+        }
+        self.skip_message_keys(header.n)?;
+        // A header repeating the key we started towards has no receiving
+        // chain behind it yet.
+        let ckr = self.ckr.ok_or_else(|| {
+            let err = "Ratchet has no receiving chain yet".to_string();
+            error!("{}", err);
+            err
+        })?;
+        let (next, mut mk) = kdf_ck(&ckr);
+        let plaintext = Self::open(&mk, ciphertext, &aad);
+        mk.zeroize();
+        self.ckr = Some(next);
+        self.nr += 1;
+        plaintext
+    }
+
+    fn open(mk: &[u8; KEY_LEN], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+        let (cipher, nonce) = message_cipher(mk)?;
+        cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad }).map_err(|_| {
+            let err = "Decryption failed".to_string();
+            error!("{}", err);
+            err
+        })
+    }
+
+    /// Stores keys of the current receiving chain up to message `until`.
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), String> {
+        let (Some(mut ckr), Some(dhr)) = (self.ckr, self.dhr) else {
+            return Ok(());
+        };
+        if until.saturating_sub(self.nr) > MAX_SKIP {
+            let err = format!("Too many skipped messages: {} after {}", until, self.nr);
+            error!("{}", err);
+            return Err(err);
+        }
+        while self.nr < until {
+            let (next, mk) = kdf_ck(&ckr);
+            self.skipped.push_back(SkippedKey { dh: dhr, n: self.nr, mk });
+            ckr.zeroize();
+            ckr = next;
+            self.nr += 1;
+        }
+        while self.skipped.len() > MAX_STORED_SKIPPED {
+            self.skipped.pop_front();
+        }
+        self.ckr = Some(ckr);
+        ckr.zeroize();
+        Ok(())
+    }
+
+    /// Turns the ratchet on a new key from the other side.
+    fn dh_ratchet(&mut self, dh_pub: &[u8; KEY_LEN]) -> Result<(), String> {
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.dhr = Some(*dh_pub);
+        let (rk, ckr) = kdf_rk(&self.rk, &dh(&self.dhs_secret, dh_pub))?;
+        self.rk = rk;
+        self.ckr = Some(ckr);
+        self.dhs_secret.zeroize();
+        (self.dhs_secret, self.dhs_public) = generate_dh();
+        let (rk, cks) = kdf_rk(&self.rk, &dh(&self.dhs_secret, dh_pub))?;
+        self.rk = rk;
+        self.cks = Some(cks);
+        Ok(())
+    }
+}
+
+/// Binds both static keys to every message, in sending order.
+fn associated_data(sender: &PublicKey, recipient: &PublicKey) -> Vec<u8> {
+    [sender.as_bytes().as_slice(), recipient.as_bytes()].concat()
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Conversation {
+    ratchet: Option<Ratchet>,
+    seen: VecDeque<String>,
+    /// First ratchet keys of sessions the peer started, so a replayed start
+    /// cannot roll the conversation back to an old session.
+    #[serde(default)]
+    started: VecDeque<String>,
+}
+
+/// Ratchet states of every conversation of one identity and the prekeys
+/// peers use to start new ones, kept encrypted in the account's data
+/// directory.
+pub struct RatchetStore {
+    file: SealedFile,
+    /// Keyed by the peer's Nostr and X25519 keys, see `conversation_key`.
+    conversations: BTreeMap<String, Conversation>,
+    pub prekeys: PrekeyStore,
+}
+
+/// Sessions are kept per Nostr key and X25519 key together, so events from
+/// one npub can never advance or replace the session of another that uses
+/// the same X25519 key.
+fn conversation_key(npub: &str, their_static: &PublicKey) -> String {
+    format!("{}:{}", npub, hex::encode(their_static.as_bytes()))
+}
+
+impl RatchetStore {
+    /// Loads the store of `our_static`. Without a readable file, it starts
+    /// without sessions and replaces the file on its first save.
+    pub fn open(data_dir: &Path, our_static: &StaticSecret) -> Self {
+        let file = SealedFile::new(data_dir, our_static, "ratchets", "sessions");
+        // Losing the sessions only costs the current ones; peers start new
+        // ones with their next message.
+        let conversations: BTreeMap<String, Conversation> = file.load().unwrap_or_else(|e| {
+            error!("Starting with empty ratchet store: {}", e);
+            None
+        }).unwrap_or_default();
+        debug!("Loaded {} conversations from ratchet store", conversations.len());
+        RatchetStore { file, conversations, prekeys: PrekeyStore::open(data_dir, our_static) }
+    }
+
+    fn save(&self) -> Result<(), String> {
+        self.file.store(&self.conversations)
+    }
+
+    /// X25519 key of the peer with Nostr key `npub`, if we talked before.
+    pub fn peer_key(&self, npub: &str) -> Option<PublicKey> {
+        self.conversations.iter()
+            .filter(|(_, conversation)| conversation.ratchet.is_some())
+            .find_map(|(key, _)| key.strip_prefix(npub)?.strip_prefix(':'))
+            .and_then(|peer| decode_key("peer key", peer).ok())
+            .map(PublicKey::from)
+    }
+
+    pub fn has_session(&self, npub: &str, their_static: &PublicKey) -> bool {
+        self.conversations.get(&conversation_key(npub, their_static))
+            .is_some_and(|conversation| conversation.ratchet.is_some())
+    }
+
+    /// Whether `their_static` is the key of a session with a Nostr key other
+    /// than `npub`, so events from `npub` carrying it are not theirs.
+    pub fn bound_elsewhere(&self, npub: &str, their_static: &PublicKey) -> bool {
+        let suffix = format!(":{}", hex::encode(their_static.as_bytes()));
+        self.conversations.iter().any(|(key, conversation)| {
+            conversation.ratchet.is_some() && key.ends_with(&suffix) && key.strip_suffix(&suffix) != Some(npub)
+        })
+    }
+
+    /// Encrypts a message to `their_static`, starting a session if there is
+    /// none: with X3DH from `bundle` if the peer published one, otherwise
+    /// from both static keys. The advanced state is saved before the message
+    /// is returned, so a message key is never used twice.
+    pub fn encrypt(&mut self, our_static: &StaticSecret, their_static: &PublicKey, npub: &str, bundle: Option<&PeerBundle>, plaintext: &[u8]) -> Result<(Header, Vec<u8>), String> {
+        let conversation = self.conversations.entry(conversation_key(npub, their_static)).or_default();
+        if conversation.ratchet.is_none() {
+            conversation.ratchet = Some(match bundle.filter(|bundle| bundle.identity == *their_static) {
+                Some(bundle) => {
+                    info!("Starting a new ratchet session from prekey bundle");
+                    Ratchet::initiate_x3dh(our_static, bundle)?
+                }
+                None => {
+                    info!("Starting a new ratchet session");
+                    Ratchet::initiate(our_static, their_static)?
+                }
+            });
+        }
+        let ad = associated_data(&PublicKey::from(our_static), their_static);
+        let sealed = conversation.ratchet.as_mut().unwrap().encrypt(plaintext, &ad)?;
+        self.save()?;
+        Ok(sealed)
+    }
+
+    /// Decrypts a message from `their_static`, Nostr key `npub`, carried in
+    /// event `event_id`. Returns `None` for an event that was already
+    /// processed.
+    ///
+    /// A message that does not fit the current session but is marked `init`
+    /// starts a new one, which replaces the current session unless both sides
+    /// started one at the same time. Then the side with the lower public key
+    /// keeps its own, so both end up in the same session.
+    pub fn decrypt(&mut self, our_static: &StaticSecret, their_static: &PublicKey, npub: &str, event_id: &str, header: &Header, ciphertext: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let our_public = PublicKey::from(our_static);
+        let conversation = self.conversations.entry(conversation_key(npub, their_static)).or_default();
+        if conversation.seen.iter().any(|seen| seen == event_id) {
+            debug!("Skipping already processed event {}", event_id);
+            return Ok(None);
+        }
+        let ad = associated_data(their_static, &our_public);
+        let current = conversation.ratchet.as_mut().map(|ratchet| ratchet.decrypt(header, ciphertext, &ad));
+        let plaintext = match current {
+            Some(Ok(plaintext)) => plaintext,
+            current if header.init => {
+                if conversation.started.contains(&header.dh) {
+                    let err = "Message belongs to an old session".to_string();
+                    error!("{}", err);
+                    return Err(err);
+                }
+                let mut fresh = match &header.x3dh {
+                    Some(handshake) => {
+                        let spk = self.prekeys.signed_prekey(&handshake.spk).ok_or_else(|| {
+                            let err = "Session start used an unknown or expired signed prekey".to_string();
+                            error!("{}", err);
+                            err
+                        })?;
+                        let opk = match &handshake.opk {
+                            Some(opk) => Some(self.prekeys.one_time_prekey(opk).ok_or_else(|| {
+                                let err = "Session start used an unknown or spent one-time prekey".to_string();
+                                error!("{}", err);
+                                err
+                            })?),
+                            None => None,
+                        };
+                        Ratchet::respond_x3dh(our_static, their_static, &spk, opk.as_ref(), handshake)?
+                    }
+                    None => Ratchet::respond(our_static, their_static)?,
+                };
+                let plaintext = fresh.decrypt(header, ciphertext, &ad)?;
+                if let Some(opk) = header.x3dh.as_ref().and_then(|handshake| handshake.opk.as_ref()) {
+                    self.prekeys.consume_one_time_prekey(opk)?;
+                }
+                let keep_ours = matches!(&conversation.ratchet, Some(ours) if !ours.confirmed)
+                    && our_public.as_bytes() < their_static.as_bytes();
+                if keep_ours {
+                    debug!("Both sides started a session, keeping ours");
+                } else {
+                    if current.is_some() {
+                        warn!("Peer started a new ratchet session, replacing ours");
+                    }
+                    conversation.ratchet = Some(fresh);
+                    conversation.started.push_back(header.dh.clone());
+                    while conversation.started.len() > MAX_STARTED {
+                        conversation.started.pop_front();
+                    }
+                }
+                plaintext
+            }
+            Some(Err(e)) => return Err(e),
+            None => {
+                let err = "No ratchet session for this message".to_string();
+                error!("{}", err);
+                return Err(err);
+            }
+        };
+        conversation.seen.push_back(event_id.to_string());
+        while conversation.seen.len() > MAX_SEEN_EVENTS {
+            conversation.seen.pop_front();
+        }
+        self.save()?;
+        Ok(Some(plaintext))
+    }
+}
+
+/// Shreds the sessions and prekeys of every identity in `data_dir` but
+/// `our_static`, for when the account they belong to is wiped.
+pub fn shred_other_stores(data_dir: &Path, our_static: &StaticSecret) -> Result<(), String> {
+    storage::shred_others(data_dir, "sessions", &SealedFile::new(data_dir, our_static, "ratchets", "sessions"))?;
+    prekeys::shred_other_stores(data_dir, our_static)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    struct Party {
+        secret: StaticSecret,
+        public: PublicKey,
+        store: RatchetStore,
+        dir: std::path::PathBuf,
+        npub: String,
+    }
+
+    impl Party {
+        fn new() -> Self {
+            let mut id = [0u8; 8];
+            OsRng.fill_bytes(&mut id);
+            let dir = std::env::temp_dir().join(format!("dumbchat-ratchet-{}", hex::encode(id)));
+            let secret = StaticSecret::random_from_rng(OsRng);
+            let store = RatchetStore::open(&dir, &secret);
+            let npub = format!("npub-{}", hex::encode(id));
+            Party { public: PublicKey::from(&secret), secret, store, dir, npub }
+        }
+
+        fn send(&mut self, to: &Party, text: &str) -> (Header, Vec<u8>) {
+            self.store.encrypt(&self.secret, &to.public, &to.npub, None, text.as_bytes()).unwrap()
+        }
+
+        fn receive(&mut self, from: &Party, event_id: &str, message: &(Header, Vec<u8>)) -> Result<Option<Vec<u8>>, String> {
+            self.store.decrypt(&self.secret, &from.public, &from.npub, event_id, &message.0, &message.1)
+        }
+    }
+
+    impl Drop for Party {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn out_of_order_messages_decrypt() {
+        let (mut alice, mut bob) = (Party::new(), Party::new());
+        let sent: Vec<_> = (0..4).map(|i| alice.send(&bob, &format!("a{}", i))).collect();
+        for i in [3, 0, 2, 1] {
+            let plaintext = bob.receive(&alice, &format!("ev{}", i), &sent[i]).unwrap().unwrap();
+            assert_eq!(plaintext, format!("a{}", i).as_bytes());
+        }
+        let reply = bob.send(&alice, "b0");
+        assert_eq!(alice.receive(&bob, "reply", &reply).unwrap().unwrap(), b"b0");
+        let next = alice.send(&bob, "a4");
+        assert_eq!(bob.receive(&alice, "ev4", &next).unwrap().unwrap(), b"a4");
+    }
+
+    #[test]
+    fn replays_are_skipped_or_refused() {
+        let (mut alice, mut bob) = (Party::new(), Party::new());
+        let first = alice.send(&bob, "a0");
+        let second = alice.send(&bob, "a1");
+        assert_eq!(bob.receive(&alice, "ev0", &first).unwrap().unwrap(), b"a0");
+        assert_eq!(bob.receive(&alice, "ev1", &second).unwrap().unwrap(), b"a1");
+        // The same event again is recognized and skipped.
+        assert_eq!(bob.receive(&alice, "ev1", &second).unwrap(), None);
+        // The same message in a new event has no key left to open it.
+        assert!(bob.receive(&alice, "ev1-again", &second).is_err());
+        assert!(bob.receive(&alice, "ev0-again", &first).is_err());
+        // Neither disturbed the session.
+        let third = alice.send(&bob, "a2");
+        assert_eq!(bob.receive(&alice, "ev2", &third).unwrap().unwrap(), b"a2");
+    }
+
+    #[test]
+    fn skipping_too_far_ahead_is_refused() {
+        let (mut alice, mut bob) = (Party::new(), Party::new());
+        let first = alice.send(&bob, "a0");
+        assert_eq!(bob.receive(&alice, "ev0", &first).unwrap().unwrap(), b"a0");
+        for _ in 0..=MAX_SKIP {
+            alice.send(&bob, "lost");
+        }
+        let far = alice.send(&bob, "far");
+        assert!(bob.receive(&alice, "far", &far).is_err());
+    }
+
+    #[test]
+    fn sessions_are_kept_per_npub() {
+        let (mut alice, mut bob) = (Party::new(), Party::new());
+        let first = alice.send(&bob, "a0");
+        assert_eq!(bob.receive(&alice, "ev0", &first).unwrap().unwrap(), b"a0");
+        let reply = bob.send(&alice, "b0");
+        assert_eq!(alice.receive(&bob, "reply", &reply).unwrap().unwrap(), b"b0");
+        assert_eq!(bob.store.peer_key(&alice.npub), Some(alice.public));
+        assert!(bob.store.bound_elsewhere("npub-mallory", &alice.public));
+        assert!(!bob.store.bound_elsewhere(&alice.npub, &alice.public));
+        // Relayed under another npub, the message finds no session.
+        let second = alice.send(&bob, "a1");
+        assert!(bob.store.decrypt(&bob.secret, &alice.public, "npub-mallory", "ev1", &second.0, &second.1).is_err());
+        assert_eq!(bob.receive(&alice, "ev1", &second).unwrap().unwrap(), b"a1");
+    }
+
+    #[test]
+    fn message_before_a_receiving_chain_is_refused() {
+        let (alice, bob) = (StaticSecret::random_from_rng(OsRng), StaticSecret::random_from_rng(OsRng));
+        let bob_public = PublicKey::from(&bob);
+        let mut ratchet = Ratchet::initiate(&alice, &bob_public).unwrap();
+        // Claims to come from the key the session was started towards.
+        let header = Header { dh: hex::encode(bob_public.as_bytes()), pn: 0, n: 0, init: false, x3dh: None };
+        assert!(ratchet.decrypt(&header, &[0u8; 32], b"").is_err());
+        assert!(ratchet.encrypt(b"still usable", b"").is_ok());
+    }
+
+    #[test]
+    fn tampered_message_fails_without_breaking_the_session() {
+        let (mut alice, mut bob) = (Party::new(), Party::new());
+        let message = alice.send(&bob, "a0");
+        let mut tampered = message.clone();
+        tampered.1[0] ^= 1;
+        assert!(bob.receive(&alice, "bad", &tampered).is_err());
+        assert_eq!(bob.receive(&alice, "ev0", &message).unwrap().unwrap(), b"a0");
+    }
+}
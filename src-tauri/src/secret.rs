@@ -0,0 +1,58 @@
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use tracing::error;
+use zeroize::Zeroizing;
+
+/// A private key in text form (hex or bech32) that is wiped from memory when
+/// dropped.
+///
+/// It implements neither `Serialize` nor `Clone`, so it cannot end up in a
+/// `Response` or be copied around by accident, and `Debug` prints a
+/// placeholder. The contents are only reachable through `expose`.
+pub struct SecretString(Zeroizing<String>);
+
+impl SecretString {
+    pub fn new(value: String) -> Self {
+        SecretString(Zeroizing::new(value))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Decodes a 64-character hex key into bytes that are wiped on drop.
+    pub fn decode_hex_key(&self) -> Result<Zeroizing<[u8; 32]>, String> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        hex::decode_to_slice(self.expose(), key.as_mut_slice()).map_err(|e| {
+            let err = format!("Invalid private key: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        Ok(key)
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString([REDACTED])")
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(SecretString::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_is_redacted() {
+        let secret: SecretString = serde_json::from_str(&format!("\"{}\"", "ab".repeat(32))).unwrap();
+        assert_eq!(format!("{:?}", secret), "SecretString([REDACTED])");
+        assert_eq!(*secret.decode_hex_key().unwrap(), [0xab; 32]);
+        assert!(SecretString::new("not hex".to_string()).decode_hex_key().is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, read};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{debug, error};
+
+use crate::keystore;
+
+const SETTINGS_FILE: &str = "settings.json";
+
+/// Idle minutes before a session locks itself, for accounts that never
+/// changed it.
+pub const DEFAULT_AUTO_LOCK_MINUTES: u32 = 15;
+/// Failed unlocks in a row after which only the recovery key is accepted.
+pub const DEFAULT_RECOVERY_KEY_AFTER_FAILURES: u32 = 10;
+
+/// Per-account preferences, kept in the account's data directory so they
+/// travel with it in bundles.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Settings {
+    /// Minutes of inactivity before the session locks. 0 turns auto-lock off.
+    pub auto_lock_minutes: u32,
+    /// Failed unlocks in a row after which the app refuses the password alone
+    /// and asks for the recovery key. 0 never requires it. Like the counter
+    /// it is checked against, this is only enforced by the app and is undone
+    /// by editing the file; see `LoginAttempts`.
+    pub recovery_key_after_failures: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            auto_lock_minutes: DEFAULT_AUTO_LOCK_MINUTES,
+            recovery_key_after_failures: DEFAULT_RECOVERY_KEY_AFTER_FAILURES,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the settings in `data_dir`, falling back to the defaults when
+    /// the account has none yet.
+    pub fn load(data_dir: &Path) -> Result<Self, String> {
+        let path = data_dir.join(SETTINGS_FILE);
+        if !path.exists() {
+            debug!("No settings at {:?}, using defaults", path);
+            return Ok(Settings::default());
+        }
+        let data = read(&path).map_err(|e| {
+            let err = format!("Failed to read settings: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        serde_json::from_slice(&data).map_err(|e| {
+            let err = format!("Settings file is corrupted: {}", e);
+            error!("{}", err);
+            err
+        })
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result<(), String> {
+        create_dir_all(data_dir).map_err(|e| {
+            let err = format!("Failed to create {:?}: {}", data_dir, e);
+            error!("{}", err);
+            err
+        })?;
+        let data = serde_json::to_vec_pretty(self).map_err(|e| {
+            let err = format!("Settings serialization failed: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        keystore::save(&data_dir.join(SETTINGS_FILE), &data)
+    }
+
+    pub fn auto_lock(&self) -> Option<Duration> {
+        (self.auto_lock_minutes > 0).then(|| Duration::from_secs(u64::from(self.auto_lock_minutes) * 60))
+    }
+}
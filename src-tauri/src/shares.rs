@@ -0,0 +1,283 @@
+use rand::{rngs::OsRng, RngCore};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use tracing::{debug, error};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Text prefix of every share, which also names the share format version.
+const PREFIX: &str = "DCS1";
+const SHARE_VERSION: u8 = 1;
+
+const SECRET_LEN: usize = 32;
+const ACCOUNT_LEN: usize = 8;
+const SPLIT_ID_LEN: usize = 4;
+const CHECKSUM_LEN: usize = 4;
+/// `version || account || split_id || threshold || index || value || checksum`.
+const SHARE_LEN: usize = 1 + ACCOUNT_LEN + SPLIT_ID_LEN + 2 + SECRET_LEN + CHECKSUM_LEN;
+
+/// A share ready to hand out. `text` is grouped for copying by hand; `qr`
+/// is the same share in a form that fits a QR code's alphanumeric mode.
+#[derive(Serialize)]
+pub struct ExportedShare {
+    pub index: u8,
+    pub text: String,
+    pub qr: String,
+}
+
+/// One share of a recovery key split with Shamir's scheme over GF(2^8).
+///
+/// Besides the share itself it carries the account it belongs to and a
+/// random id of the split it came from, so shares of different accounts or
+/// of an earlier split are refused before anything is reconstructed, and a
+/// checksum that catches typos.
+struct Share {
+    account: [u8; ACCOUNT_LEN],
+    split_id: [u8; SPLIT_ID_LEN],
+    threshold: u8,
+    index: u8,
+    value: Zeroizing<[u8; SECRET_LEN]>,
+}
+
+/// Short public identifier of an account, derived from its Nostr public key
+/// so it survives renames and moves between devices.
+pub fn account_tag(public_key: &[u8]) -> [u8; ACCOUNT_LEN] {
+    let digest = Sha256::new()
+        .chain_update(b"dumbchat recovery share account")
+        .chain_update(public_key)
+        .finalize();
+    let mut tag = [0u8; ACCOUNT_LEN];
+    tag.copy_from_slice(&digest[..ACCOUNT_LEN]);
+    tag
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Sha256::digest(data);
+    let mut sum = [0u8; CHECKSUM_LEN];
+    sum.copy_from_slice(&digest[..CHECKSUM_LEN]);
+    sum
+}
+
+/// Multiplication in GF(2^8) modulo the AES polynomial, without branches on
+/// secret values.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse, as `a^254`.
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exponent = 254u8;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+impl Share {
+    fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut out = Zeroizing::new(Vec::with_capacity(SHARE_LEN));
+        out.push(SHARE_VERSION);
+        out.extend_from_slice(&self.account);
+        out.extend_from_slice(&self.split_id);
+        out.push(self.threshold);
+        out.push(self.index);
+        out.extend_from_slice(self.value.as_slice());
+        let sum = checksum(&out);
+        out.extend_from_slice(&sum);
+        out
+    }
+
+    fn export(&self) -> ExportedShare {
+        let mut encoded = hex::encode_upper(self.to_bytes().as_slice());
+        let groups: Vec<&str> = encoded.as_bytes().chunks(4)
+            .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+            .collect();
+        let share = ExportedShare {
+            index: self.index,
+            text: format!("{}-{}", PREFIX, groups.join("-")),
+            qr: format!("{}:{}", PREFIX, encoded),
+        };
+        encoded.zeroize();
+        share
+    }
+
+    /// Parses a share in either exported form. Spaces, dashes and case do
+    /// not matter. `position` numbers the share in error messages.
+    fn parse(text: &str, position: usize) -> Result<Self, String> {
+        let reject = |reason: &str| {
+            error!("Recovery share {} rejected: {}", position, reason);
+            format!("Recovery share {} {}", position, reason)
+        };
+        let cleaned = Zeroizing::new(text.chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .collect::<String>()
+            .to_uppercase());
+        let body = cleaned.strip_prefix(PREFIX)
+            .map(|body| body.strip_prefix(':').unwrap_or(body))
+            .ok_or_else(|| reject("is not a DumbChat recovery share"))?;
+        let bytes = Zeroizing::new(hex::decode(body).map_err(|_| reject("contains invalid characters"))?);
+        if bytes.len() != SHARE_LEN {
+            return Err(reject("is too short or too long"));
+        }
+        let (data, sum) = bytes.split_at(SHARE_LEN - CHECKSUM_LEN);
+        if checksum(data) != sum {
+            return Err(reject("has a typo: its checksum does not match"));
+        }
+        if data[0] != SHARE_VERSION {
+            return Err(reject(&format!("has unsupported version {}", data[0])));
+        }
+        let mut account = [0u8; ACCOUNT_LEN];
+        account.copy_from_slice(&data[1..1 + ACCOUNT_LEN]);
+        let mut split_id = [0u8; SPLIT_ID_LEN];
+        split_id.copy_from_slice(&data[1 + ACCOUNT_LEN..1 + ACCOUNT_LEN + SPLIT_ID_LEN]);
+        let threshold = data[1 + ACCOUNT_LEN + SPLIT_ID_LEN];
+        let index = data[2 + ACCOUNT_LEN + SPLIT_ID_LEN];
+        if threshold < 2 || index == 0 {
+            return Err(reject("is malformed"));
+        }
+        let mut value = Zeroizing::new([0u8; SECRET_LEN]);
+        value.copy_from_slice(&data[3 + ACCOUNT_LEN + SPLIT_ID_LEN..]);
+        Ok(Share { account, split_id, threshold, index, value })
+    }
+}
+
+/// Splits `secret` into `shares` shares, any `threshold` of which rebuild it.
+pub fn split(secret: &[u8; SECRET_LEN], account: [u8; ACCOUNT_LEN], shares: u8, threshold: u8) -> Result<Vec<ExportedShare>, String> {
+    if threshold < 2 || shares < threshold {
+        let err = format!("Cannot split into {} shares with a threshold of {}: the threshold must be at least 2 and at most the number of shares", shares, threshold);
+        error!("{}", err);
+        return Err(err);
+    }
+    let mut split_id = [0u8; SPLIT_ID_LEN];
+    OsRng.fill_bytes(&mut split_id);
+    // Coefficients of degree 1 to threshold - 1 for each secret byte; the
+    // constant term is the byte itself.
+    let mut coefficients = Zeroizing::new(vec![0u8; (threshold as usize - 1) * SECRET_LEN]);
+    OsRng.fill_bytes(&mut coefficients);
+    let exported = (1..=shares).map(|x| {
+        let mut value = Zeroizing::new([0u8; SECRET_LEN]);
+        for (i, byte) in value.iter_mut().enumerate() {
+            let mut y = 0u8;
+            for degree in (1..threshold as usize).rev() {
+                y = gf_mul(y, x) ^ coefficients[(degree - 1) * SECRET_LEN + i];
+            }
+            *byte = gf_mul(y, x) ^ secret[i];
+        }
+        Share { account, split_id, threshold, index: x, value }.export()
+    }).collect();
+    debug!("Split recovery key into {} shares, {} needed", shares, threshold);
+    Ok(exported)
+}
+
+/// Rebuilds a recovery key from shares in text or QR form. Shares are checked
+/// one by one and against each other before anything is combined.
+pub fn combine(texts: &[String]) -> Result<Zeroizing<[u8; SECRET_LEN]>, String> {
+    let shares = texts.iter()
+        .filter(|text| !text.trim().is_empty())
+        .enumerate()
+        .map(|(i, text)| Share::parse(text, i + 1))
+        .collect::<Result<Vec<_>, _>>()?;
+    let first = shares.first().ok_or_else(|| {
+        let err = "No recovery shares given".to_string();
+        error!("{}", err);
+        err
+    })?;
+    if shares.iter().any(|share| share.account != first.account) {
+        error!("Recovery shares from different accounts");
+        return Err("These recovery shares belong to different accounts".to_string());
+    }
+    if shares.iter().any(|share| share.split_id != first.split_id || share.threshold != first.threshold) {
+        error!("Recovery shares from different splits");
+        return Err("These recovery shares come from different splits of the recovery key".to_string());
+    }
+    let mut seen = BTreeSet::new();
+    if let Some(duplicate) = shares.iter().find(|share| !seen.insert(share.index)) {
+        error!("Recovery share {} given twice", duplicate.index);
+        return Err(format!("Recovery share number {} was entered twice", duplicate.index));
+    }
+    if shares.len() < first.threshold as usize {
+        let err = format!("{} recovery shares are needed, only {} given", first.threshold, shares.len());
+        error!("{}", err);
+        return Err(err);
+    }
+    let used = &shares[..first.threshold as usize];
+    // Lagrange interpolation at x = 0. Subtraction in GF(2^8) is XOR.
+    let mut secret = Zeroizing::new([0u8; SECRET_LEN]);
+    for (j, share) in used.iter().enumerate() {
+        let mut basis = 1u8;
+        for (m, other) in used.iter().enumerate() {
+            if m != j {
+                basis = gf_mul(basis, gf_mul(other.index, gf_inv(other.index ^ share.index)));
+            }
+        }
+        for (byte, y) in secret.iter_mut().zip(share.value.iter()) {
+            *byte ^= gf_mul(basis, *y);
+        }
+    }
+    debug!("Recovery key rebuilt from {} shares", used.len());
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: [u8; SECRET_LEN] = [42u8; SECRET_LEN];
+
+    fn texts(shares: &[ExportedShare], pick: &[usize]) -> Vec<String> {
+        pick.iter().map(|&i| shares[i].text.clone()).collect()
+    }
+
+    #[test]
+    fn any_threshold_shares_rebuild_the_secret() {
+        let shares = split(&SECRET, account_tag(b"alice"), 5, 3).unwrap();
+        assert_eq!(shares.len(), 5);
+        for pick in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            assert_eq!(*combine(&texts(&shares, &pick)).unwrap(), SECRET);
+        }
+        // The QR form is accepted too, and so is lowercase text.
+        let mixed = vec![shares[0].qr.clone(), shares[3].text.to_lowercase(), shares[4].text.clone()];
+        assert_eq!(*combine(&mixed).unwrap(), SECRET);
+    }
+
+    #[test]
+    fn too_few_or_repeated_shares_are_refused() {
+        let shares = split(&SECRET, account_tag(b"alice"), 5, 3).unwrap();
+        assert!(combine(&texts(&shares, &[0, 1])).is_err());
+        assert!(combine(&texts(&shares, &[0, 1, 1])).is_err());
+        assert!(split(&SECRET, account_tag(b"alice"), 2, 3).is_err());
+        assert!(split(&SECRET, account_tag(b"alice"), 3, 1).is_err());
+    }
+
+    #[test]
+    fn typo_fails_the_checksum() {
+        let shares = split(&SECRET, account_tag(b"alice"), 3, 2).unwrap();
+        let mut typo: Vec<char> = shares[0].text.chars().collect();
+        let at = PREFIX.len() + 8;
+        typo[at] = if typo[at] == '0' { '1' } else { '0' };
+        let err = combine(&[typo.into_iter().collect(), shares[1].text.clone()]).err().unwrap();
+        assert!(err.contains("checksum"), "{}", err);
+    }
+
+    #[test]
+    fn shares_of_other_accounts_or_splits_are_refused() {
+        let alice = split(&SECRET, account_tag(b"alice"), 3, 2).unwrap();
+        let bob = split(&SECRET, account_tag(b"bob"), 3, 2).unwrap();
+        let again = split(&SECRET, account_tag(b"alice"), 3, 2).unwrap();
+        assert!(combine(&[alice[0].text.clone(), bob[1].text.clone()]).is_err());
+        assert!(combine(&[alice[0].text.clone(), again[1].text.clone()]).is_err());
+    }
+}
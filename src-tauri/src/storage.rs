@@ -0,0 +1,117 @@
+use aes_gcm::{aead::{Aead, KeyInit, OsRng, Payload}, Aes256Gcm, Nonce, Key};
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+use std::fs::{create_dir_all, read, read_dir};
+use std::path::{Path, PathBuf};
+use tracing::{debug, error};
+use x25519_dalek::StaticSecret;
+use zeroize::Zeroizing;
+
+use crate::keystore;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const SALT: &[u8] = b"dumbchat/storage/v1";
+
+/// A file in an account's data directory encrypted under a key derived from
+/// one identity's X25519 secret.
+///
+/// The file name is derived from the secret too, so each identity in an
+/// account, such as a duress decoy, has files that only it can find and read.
+pub struct SealedFile {
+    path: PathBuf,
+    key: Zeroizing<[u8; KEY_LEN]>,
+    label: &'static str,
+}
+
+impl SealedFile {
+    /// The file `label` of `our_static`, named `<prefix>-<tag>.bin`.
+    pub fn new(data_dir: &Path, our_static: &StaticSecret, label: &'static str, prefix: &str) -> Self {
+        let secret = Zeroizing::new(our_static.to_bytes());
+        let hk = Hkdf::<Sha256>::new(Some(SALT), secret.as_slice());
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        let mut name = [0u8; 8];
+        hk.expand(label.as_bytes(), key.as_mut_slice()).expect("32 bytes is a valid HKDF output length");
+        hk.expand(format!("{} file name", label).as_bytes(), &mut name).expect("8 bytes is a valid HKDF output length");
+        SealedFile {
+            path: data_dir.join(format!("{}-{}.bin", prefix, hex::encode(name))),
+            key,
+            label,
+        }
+    }
+
+    /// Reads and decrypts the file, or `None` if there is none yet.
+    pub fn load<T: DeserializeOwned>(&self) -> Result<Option<T>, String> {
+        if !self.path.exists() {
+            debug!("No {} file at {:?}", self.label, self.path);
+            return Ok(None);
+        }
+        let data = read(&self.path).map_err(|e| {
+            let err = format!("Failed to read {} file: {}", self.label, e);
+            error!("{}", err);
+            err
+        })?;
+        if data.len() < NONCE_LEN {
+            error!("{} file truncated", self.label);
+            return Err(format!("The {} file is corrupted", self.label));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(self.key.as_slice()));
+        let aad = self.label.as_bytes();
+        let plaintext = Zeroizing::new(cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad }).map_err(|_| {
+            let err = format!("The {} file is corrupted", self.label);
+            error!("{}", err);
+            err
+        })?);
+        serde_json::from_slice(&plaintext).map(Some).map_err(|e| {
+            let err = format!("The {} file is corrupted: {}", self.label, e);
+            error!("{}", err);
+            err
+        })
+    }
+
+    /// Encrypts `value` and replaces the file with it.
+    pub fn store<T: Serialize>(&self, value: &T) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent).map_err(|e| {
+                let err = format!("Failed to create {:?}: {}", parent, e);
+                error!("{}", err);
+                err
+            })?;
+        }
+        let plaintext = Zeroizing::new(serde_json::to_vec(value).map_err(|e| {
+            let err = format!("Serialization of {} failed: {}", self.label, e);
+            error!("{}", err);
+            err
+        })?);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(self.key.as_slice()));
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let aad = self.label.as_bytes();
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: &plaintext, aad }).map_err(|e| {
+            let err = format!("Encryption failed: {}", e);
+            error!("{}", err);
+            err
+        })?;
+        keystore::save(&self.path, &[nonce.as_slice(), &ciphertext].concat())
+    }
+}
+
+/// Shreds every file named `<prefix>-*.bin` in `data_dir` except `keep`,
+/// for when the identities they belong to are wiped.
+pub fn shred_others(data_dir: &Path, prefix: &str, keep: &SealedFile) -> Result<(), String> {
+    let Ok(entries) = read_dir(data_dir) else {
+        return Ok(());
+    };
+    let prefix = format!("{}-", prefix);
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if path != keep.path && name.starts_with(&prefix) && name.ends_with(".bin") {
+            keystore::shred(&path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,330 @@
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use tracing::{error, info, warn};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::attempts::now;
+use crate::message::Format;
+use crate::storage::{self, SealedFile};
+
+/// Version of the safety number derivation, shown in the QR form.
+const SAFETY_NUMBER_VERSION: u8 = 1;
+/// Hash iterations per fingerprint, which makes finding a key with a given
+/// safety number costlier.
+const FINGERPRINT_ITERATIONS: usize = 5200;
+/// Digits per fingerprint: 6 groups of 5.
+const FINGERPRINT_GROUPS: usize = 6;
+
+/// What we know about the keys of one contact.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Contact {
+    /// Pinned X25519 key, hex: the first one seen, or one the user accepted
+    /// since. Empty for contacts we only know the Nostr key of.
+    pub x_pub: String,
+    pub first_seen: u64,
+    /// Set by the user after comparing safety numbers.
+    pub verified: bool,
+    /// A different key seen after the contact was verified, waiting for the
+    /// user to accept it.
+    #[serde(default)]
+    pub pending_key: Option<String>,
+    /// ed25519 key the contact signs messages with, hex: the announced one,
+    /// or else the first one seen.
+    #[serde(default)]
+    pub signing_key: Option<String>,
+    /// Format we send to the contact in, chosen by the user. What we receive
+    /// from them never changes it, so no one can downgrade it.
+    #[serde(default)]
+    pub format: Format,
+    /// Whether messages to the contact are sealed and gift wrapped
+    /// (NIP-59), which hides who sent them and when from relays. NIP-44
+    /// messages always are, as NIP-17 has it, and NIP-04 ones never.
+    #[serde(default)]
+    pub gift_wrap: bool,
+    /// Ids of legacy (version 1) events read from the contact while those
+    /// were accepted, so that they stay readable once they no longer are.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub legacy_events: BTreeSet<String>,
+}
+
+/// Outcome of seeing a key for a contact.
+#[derive(PartialEq, Debug)]
+pub enum Trust {
+    /// First key seen for the contact, now pinned.
+    New,
+    /// The pinned key.
+    Pinned { verified: bool },
+    /// A key other than the pinned one. An unverified contact's new key is
+    /// pinned in its place; a verified contact's is held until accepted.
+    Changed { verified: bool },
+}
+
+/// Keys seen per contact, trust on first use, kept encrypted in the
+/// account's data directory.
+pub struct TrustStore {
+    file: SealedFile,
+    /// Keyed by the contact's Nostr public key, bech32.
+    contacts: BTreeMap<String, Contact>,
+}
+
+impl TrustStore {
+    pub fn open(data_dir: &Path, our_static: &StaticSecret) -> Self {
+        let file = SealedFile::new(data_dir, our_static, "contacts", "contacts");
+        // Without the file every contact is new again, which loses pins but
+        // never blocks anyone.
+        let contacts = file.load().unwrap_or_else(|e| {
+            error!("Starting with empty trust store: {}", e);
+            None
+        }).unwrap_or_default();
+        TrustStore { file, contacts }
+    }
+
+    pub fn contacts(&self) -> &BTreeMap<String, Contact> {
+        &self.contacts
+    }
+
+    pub fn get(&self, npub: &str) -> Option<&Contact> {
+        self.contacts.get(npub)
+    }
+
+    /// Records that `npub` uses `x_pub`.
+    pub fn observe(&mut self, npub: &str, x_pub: &PublicKey) -> Result<Trust, String> {
+        let key = hex::encode(x_pub.as_bytes());
+        let trust = match self.contacts.get_mut(npub) {
+            None => {
+                info!("Pinning first key seen for {}", npub);
+                self.contacts.insert(npub.to_string(), Contact {
+                    x_pub: key,
+                    first_seen: now(),
+                    verified: false,
+                    pending_key: None,
+                    signing_key: None,
+                    format: Format::default(),
+                    gift_wrap: false,
+                    legacy_events: BTreeSet::new(),
+                });
+                Trust::New
+            }
+            Some(contact) if contact.x_pub.is_empty() => {
+                info!("Pinning first key seen for {}", npub);
+                contact.x_pub = key;
+                Trust::New
+            }
+            Some(contact) if contact.x_pub == key => return Ok(Trust::Pinned { verified: contact.verified }),
+            Some(contact) if contact.verified => {
+                if contact.pending_key.as_ref() == Some(&key) {
+                    return Ok(Trust::Changed { verified: true });
+                }
+                warn!("Key of verified contact {} changed, holding it for review", npub);
+                contact.pending_key = Some(key);
+                Trust::Changed { verified: true }
+            }
+            Some(contact) => {
+                warn!("Key of contact {} changed, pinning the new one", npub);
+                contact.x_pub = key;
+                Trust::Changed { verified: false }
+            }
+        };
+        self.file.store(&self.contacts)?;
+        Ok(trust)
+    }
+
+    /// Whether `signing_key` may sign for `npub`: it must be the key the
+    /// contact announced, if we know it, or else the one pinned for them.
+    /// A trusted key is pinned.
+    pub fn observe_signer(&mut self, npub: &str, signing_key: &VerifyingKey, announced: Option<&VerifyingKey>) -> Result<bool, String> {
+        let key = hex::encode(signing_key.as_bytes());
+        let contact = self.contact_mut(npub)?;
+        let trusted = match (announced, &contact.signing_key) {
+            (Some(announced), _) => announced == signing_key,
+            (None, Some(pinned)) => *pinned == key,
+            (None, None) => true,
+        };
+        if !trusted {
+            warn!("Message from {} signed with a key that is not theirs", npub);
+            return Ok(false);
+        }
+        if contact.signing_key.as_ref() != Some(&key) {
+            info!("Pinning signing key of {}", npub);
+            contact.signing_key = Some(key);
+            self.file.store(&self.contacts)?;
+        }
+        Ok(true)
+    }
+
+    fn contact_mut(&mut self, npub: &str) -> Result<&mut Contact, String> {
+        self.contacts.get_mut(npub).ok_or_else(|| {
+            let err = format!("Unknown contact {}", npub);
+            error!("{}", err);
+            err
+        })
+    }
+
+    /// Whether the legacy event `event_id` from `npub` was read before.
+    pub fn read_legacy(&self, npub: &str, event_id: &str) -> bool {
+        self.contacts.get(npub).is_some_and(|contact| contact.legacy_events.contains(event_id))
+    }
+
+    /// Remembers that the legacy event `event_id` from `npub` was read.
+    pub fn remember_legacy(&mut self, npub: &str, event_id: &str) -> Result<(), String> {
+        if self.contact_mut(npub)?.legacy_events.insert(event_id.to_string()) {
+            self.file.store(&self.contacts)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_verified(&mut self, npub: &str, verified: bool) -> Result<(), String> {
+        let contact = self.contact_mut(npub)?;
+        if verified && contact.pending_key.is_some() {
+            error!("Refusing to verify {} with a key change pending", npub);
+            return Err("This contact's key changed. Accept the new key before verifying it.".to_string());
+        }
+        contact.verified = verified;
+        info!("Contact {} marked {}", npub, if verified { "verified" } else { "unverified" });
+        self.file.store(&self.contacts)
+    }
+
+    /// The contact `npub`, added with only their Nostr key if new.
+    fn contact_or_insert(&mut self, npub: &str) -> &mut Contact {
+        self.contacts.entry(npub.to_string()).or_insert_with(|| Contact {
+            x_pub: String::new(),
+            first_seen: now(),
+            verified: false,
+            pending_key: None,
+            signing_key: None,
+            format: Format::default(),
+            gift_wrap: false,
+            legacy_events: BTreeSet::new(),
+        })
+    }
+
+    /// Sets the format we send to `npub` in, adding them as a contact if
+    /// need be. Clients that only read NIP-04 cannot open gift wraps, so
+    /// choosing it stops wrapping.
+    pub fn set_format(&mut self, npub: &str, format: Format) -> Result<(), String> {
+        let contact = self.contact_or_insert(npub);
+        contact.format = format;
+        if format == Format::Nip04 && contact.gift_wrap {
+            warn!("Not gift wrapping messages to {} as NIP-04", npub);
+            contact.gift_wrap = false;
+        }
+        info!("Sending to {} as {:?}", npub, format);
+        self.file.store(&self.contacts)
+    }
+
+    /// Sets whether messages to `npub` are gift wrapped, adding them as a
+    /// contact if need be.
+    pub fn set_gift_wrap(&mut self, npub: &str, gift_wrap: bool) -> Result<(), String> {
+        let contact = self.contact_or_insert(npub);
+        if gift_wrap && contact.format == Format::Nip04 {
+            error!("Refusing to gift wrap NIP-04 messages to {}", npub);
+            return Err("NIP-04 clients cannot open gift wrapped messages. Choose another format first.".to_string());
+        }
+        contact.gift_wrap = gift_wrap;
+        info!("Gift wrapping for {} {}", npub, if gift_wrap { "enabled" } else { "disabled" });
+        self.file.store(&self.contacts)
+    }
+
+    /// Pins the pending key of a verified contact. The contact is no longer
+    /// verified until safety numbers are compared again.
+    pub fn accept_key_change(&mut self, npub: &str) -> Result<(), String> {
+        let contact = self.contact_mut(npub)?;
+        let Some(key) = contact.pending_key.take() else {
+            error!("No key change pending for {}", npub);
+            return Err("No key change is pending for this contact".to_string());
+        };
+        contact.x_pub = key;
+        contact.verified = false;
+        info!("Accepted new key for {}", npub);
+        self.file.store(&self.contacts)
+    }
+}
+
+/// One party's half of a safety number: an iterated hash of their Nostr
+/// public key and X25519 key, as digits.
+fn fingerprint(nostr_pub: &[u8], x_pub: &[u8]) -> String {
+    let mut hash = Sha512::new()
+        .chain_update([0u8, SAFETY_NUMBER_VERSION])
+        .chain_update(x_pub)
+        .chain_update(nostr_pub)
+        .finalize();
+    for _ in 1..FINGERPRINT_ITERATIONS {
+        hash = Sha512::new().chain_update(hash).chain_update(x_pub).finalize();
+    }
+    hash.chunks(5)
+        .take(FINGERPRINT_GROUPS)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Safety number of a conversation, the same on both sides: both parties'
+/// fingerprints, lower first. Returns the digits and a QR payload.
+pub fn safety_number(ours: (&[u8], &PublicKey), theirs: (&[u8], &PublicKey)) -> (String, String) {
+    let mut halves = [
+        fingerprint(ours.0, ours.1.as_bytes()),
+        fingerprint(theirs.0, theirs.1.as_bytes()),
+    ];
+    halves.sort();
+    let digits = halves.join(" ");
+    let qr = format!("DCSN{}:{}", SAFETY_NUMBER_VERSION, digits.replace(' ', ""));
+    (digits, qr)
+}
+
+/// Shreds the trust stores of every identity in `data_dir` but `our_static`.
+pub fn shred_other_stores(data_dir: &Path, our_static: &StaticSecret) -> Result<(), String> {
+    storage::shred_others(data_dir, "contacts", &SealedFile::new(data_dir, our_static, "contacts", "contacts"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::aead::OsRng;
+
+    fn key() -> PublicKey {
+        PublicKey::from(&StaticSecret::random_from_rng(OsRng))
+    }
+
+    #[test]
+    fn safety_number_is_symmetric() {
+        let (alice, bob) = (key(), key());
+        let ours = safety_number((b"alice", &alice), (b"bob", &bob));
+        let theirs = safety_number((b"bob", &bob), (b"alice", &alice));
+        assert_eq!(ours, theirs);
+        assert_eq!(ours.0.split(' ').count(), 2 * FINGERPRINT_GROUPS);
+        assert!(ours.1.starts_with("DCSN1:"));
+    }
+
+    #[test]
+    fn safety_number_changes_with_either_key() {
+        let (alice, bob, mallory) = (key(), key(), key());
+        let genuine = safety_number((b"alice", &alice), (b"bob", &bob));
+        assert_ne!(genuine, safety_number((b"alice", &alice), (b"bob", &mallory)));
+        assert_ne!(genuine, safety_number((b"alice", &mallory), (b"bob", &bob)));
+    }
+
+    #[test]
+    fn verified_key_change_is_held() {
+        let mut id = [0u8; 8];
+        rand::RngCore::fill_bytes(&mut OsRng, &mut id);
+        let dir = std::env::temp_dir().join(format!("dumbchat-trust-{}", hex::encode(id)));
+        let ours = StaticSecret::random_from_rng(OsRng);
+        let (old, new) = (key(), key());
+        let mut store = TrustStore::open(&dir, &ours);
+        assert_eq!(store.observe("npub", &old).unwrap(), Trust::New);
+        assert_eq!(store.observe("npub", &old).unwrap(), Trust::Pinned { verified: false });
+        store.set_verified("npub", true).unwrap();
+        assert_eq!(store.observe("npub", &new).unwrap(), Trust::Changed { verified: true });
+        assert_eq!(store.get("npub").unwrap().x_pub, hex::encode(old.as_bytes()));
+        assert!(store.set_verified("npub", true).is_err());
+        store.accept_key_change("npub").unwrap();
+        assert_eq!(store.observe("npub", &new).unwrap(), Trust::Pinned { verified: false });
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}